@@ -1,50 +1,988 @@
-use anyhow::Result;
-use futures::future::{BoxFuture, FutureExt};
-use std::future::Future;
+use anyhow::{Error, Result};
+use futures::stream::Stream;
+use num_bigint::BigUint;
+use num_traits::{One, ToPrimitive};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 
-/// 生成每个队列交错的所有排列，每种交错情况生成完
-/// 结果会作为参数调用回调函数
+/// 剪枝条件，判断能否把队列`i`的下一个元素追加到当前交错的末尾
+///
+/// 参数依次为当前交错的结果，每个队列当前位置，及要追加的队列索引`i`。
+/// 按切换次数分多轮生成时，每一轮都要使用同样的剪枝条件，所以可以共享
+pub type Pruner = Arc<dyn Fn(&[(usize, usize)], &[usize], usize) -> bool + Send + Sync>;
+
+/// 按字典序惰性生成每个队列交错的所有排列，不使用递归
+///
+/// 每种交错情况是一个(队列索引, 队列中的位置)的列表，可以
+/// 作为`Iterator`在同步代码中使用，也可以作为`Stream`使用
+pub struct InterlacePermutations {
+    /// 每个队列的大小
+    sizes: Vec<usize>,
+    /// 每个队列当前位置
+    curs: Vec<usize>,
+    /// 存放当前交错的结果
+    result: Vec<(usize, usize)>,
+    /// 每一层下一个要尝试的队列索引，代替递归的栈
+    nexts: Vec<usize>,
+    /// 所有队列的大小之和
+    total: usize,
+    finished: bool,
+    /// 当前结果还没被返回，从指定索引开始时使用
+    pending: bool,
+    /// 只生成所有剪枝条件都允许的交错排列
+    pruners: Vec<Pruner>,
+    /// 当前交错的切换次数，随着追加及回溯更新
+    switches: usize,
+    /// 最多的切换次数，为None时不限制
+    max_switches: Option<usize>,
+}
+
+impl InterlacePermutations {
+    /// # Arguments
+    ///
+    /// * `sizes` -  每个队列的大小
+    pub fn new(sizes: Vec<usize>) -> Self {
+        let total = sizes.iter().sum();
+        InterlacePermutations {
+            curs: vec![0; sizes.len()],
+            sizes,
+            result: Vec::with_capacity(total),
+            nexts: vec![0],
+            total,
+            finished: false,
+            // 所有队列都为空时，空的交错排列是唯一的结果
+            pending: total == 0,
+            pruners: vec![],
+            switches: 0,
+            max_switches: None,
+        }
+    }
+
+    /// 增加剪枝条件，被剪掉的前缀不会再往下生成
+    pub fn with_pruner(mut self, pruner: Pruner) -> Self {
+        self.pruners.push(pruner);
+        self
+    }
+
+    /// 只生成切换次数不超过`max_switches`的交错排列
+    pub fn with_max_switches(mut self, max_switches: usize) -> Self {
+        self.max_switches = Some(max_switches);
+        self
+    }
+
+    fn is_allowed(&self, result: &[(usize, usize)], curs: &[usize], i: usize) -> bool {
+        self.pruners.iter().all(|pruner| pruner(result, curs, i))
+    }
+
+    /// 从头检查交错排列的每一步是否都被剪枝条件允许
+    fn is_all_allowed(&self, result: &[(usize, usize)]) -> bool {
+        if self
+            .max_switches
+            .is_some_and(|max_switches| switches(result) > max_switches)
+        {
+            return false;
+        }
+        let mut curs = vec![0; self.sizes.len()];
+        for (depth, &(i, _)) in result.iter().enumerate() {
+            if !self.is_allowed(&result[..depth], &curs, i) {
+                return false;
+            }
+            curs[i] += 1;
+        }
+        true
+    }
+
+    /// 从字典序中索引为`rank`的交错排列开始生成
+    ///
+    /// # Arguments
+    ///
+    /// * `sizes` -  每个队列的大小
+    /// * `rank` - 开始的交错排列的索引，从0开始
+    pub fn from_rank(sizes: Vec<usize>, rank: &BigUint) -> Result<Self> {
+        let result = interlace_unrank(&sizes, rank)?;
+        let mut permutations = Self::new(sizes);
+        // 恢复成刚生成该交错排列时的状态
+        permutations.nexts = result.iter().map(|&(i, _)| i + 1).collect();
+        permutations.nexts.push(0);
+        permutations.curs = permutations.sizes.clone();
+        permutations.switches = switches(&result);
+        permutations.result = result;
+        permutations.pending = true;
+        Ok(permutations)
+    }
+}
+
+impl Iterator for InterlacePermutations {
+    type Item = Vec<(usize, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        if self.pending {
+            self.pending = false;
+            if self.is_all_allowed(&self.result) {
+                return Some(self.result.clone());
+            }
+        }
+        loop {
+            let depth = self.result.len();
+            // 从该层下一个要尝试的队列开始，找还有剩余且没被剪掉的队列
+            let next = self.nexts[depth];
+            let curs = &self.curs;
+            let sizes = &self.sizes;
+            let result = &self.result;
+            // 切换次数已经达到上限时，只能追加上一个队列
+            let last = result.last().map(|&(i, _)| i);
+            let saturated = self
+                .max_switches
+                .is_some_and(|max_switches| self.switches >= max_switches);
+            match (next..sizes.len()).find(|&i| {
+                curs[i] < sizes[i]
+                    && !(saturated && last.is_some_and(|last| last != i))
+                    && self.is_allowed(result, curs, i)
+            }) {
+                Some(i) => {
+                    self.nexts[depth] = i + 1;
+                    if last.is_some_and(|last| last != i) {
+                        self.switches += 1;
+                    }
+                    // 把当前队列的索引及当前队列的位置放到结果里
+                    self.result.push((i, self.curs[i]));
+                    self.curs[i] += 1;
+                    self.nexts.push(0);
+                    if self.result.len() == self.total {
+                        // 所有队列都已放完，得到结果，下次调用时回溯
+                        return Some(self.result.clone());
+                    }
+                }
+                None => {
+                    if depth == 0 {
+                        self.finished = true;
+                        return None;
+                    }
+                    // 该层已经尝试完，回溯到上一层
+                    self.nexts.pop();
+                    let (i, _) = self.result.pop().unwrap();
+                    self.curs[i] -= 1;
+                    if self.result.last().is_some_and(|&(last, _)| last != i) {
+                        self.switches -= 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Stream for InterlacePermutations {
+    type Item = Vec<(usize, usize)>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.get_mut().next())
+    }
+}
+
+/// 交错排列中相邻两个元素来自不同队列的次数
+pub fn switches(batch: &[(usize, usize)]) -> usize {
+    batch.windows(2).filter(|w| w[0].0 != w[1].0).count()
+}
+
+/// 按切换次数从少到多生成交错排列，切换次数相同的按字典序
+///
+/// 切换次数少的交错排列更容易理解，而且大多数并发问题只需要很少
+/// 的切换就能出现。每一轮只生成切换次数恰好为当前值的交错排列，
+/// 需要重新枚举切换次数更少的前缀
+pub struct FewestSwitchesFirst {
+    sizes: Vec<usize>,
+    pruners: Vec<Pruner>,
+    /// 最多的切换次数
+    max_switches: usize,
+    /// 当前一轮的切换次数
+    switches: usize,
+    current: InterlacePermutations,
+}
+
+impl FewestSwitchesFirst {
+    /// # Arguments
+    ///
+    /// * `sizes` -  每个队列的大小
+    /// * `max_switches` - 最多的切换次数，为None时生成所有交错排列
+    /// * `pruners` - 每一轮都使用的剪枝条件
+    pub fn new(sizes: Vec<usize>, max_switches: Option<usize>, pruners: Vec<Pruner>) -> Self {
+        let total: usize = sizes.iter().sum();
+        // 每个非空的队列都至少要切换到一次
+        let min_switches = sizes.iter().filter(|&&size| size > 0).count().max(1) - 1;
+        let max_switches = max_switches.unwrap_or_else(|| total.max(1) - 1);
+        let current = Self::permutations(&sizes, min_switches, &pruners);
+        FewestSwitchesFirst {
+            sizes,
+            pruners,
+            max_switches,
+            switches: min_switches,
+            current,
+        }
+    }
+
+    fn permutations(sizes: &[usize], switches: usize, pruners: &[Pruner]) -> InterlacePermutations {
+        let mut permutations =
+            InterlacePermutations::new(sizes.to_vec()).with_max_switches(switches);
+        for pruner in pruners {
+            permutations = permutations.with_pruner(Arc::clone(pruner));
+        }
+        permutations
+    }
+}
+
+impl Iterator for FewestSwitchesFirst {
+    type Item = Vec<(usize, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.switches <= self.max_switches {
+            while let Some(batch) = self.current.next() {
+                // 切换次数更少的已经在之前的轮次生成过
+                if self.current.switches == self.switches {
+                    return Some(batch);
+                }
+            }
+            self.switches += 1;
+            self.current = Self::permutations(&self.sizes, self.switches, &self.pruners);
+        }
+        None
+    }
+}
+
+/// 构造一个覆盖指定先后顺序的交错排列时，每个元素最多尝试的步数，
+/// 超过时认为该先后顺序无法出现
+const PAIRWISE_BUILD_BUDGET: usize = 10_000;
+
+/// 不同队列的元素两两之间出现过的先后顺序
+pub struct PairCoverage {
+    sizes: Vec<usize>,
+    /// 每个队列第一个元素在矩阵中的位置
+    offsets: Vec<usize>,
+    total: usize,
+    /// `before[x * total + y]`表示是否出现过`x`在`y`之前
+    before: Vec<bool>,
+}
+
+impl PairCoverage {
+    pub fn new(sizes: Vec<usize>) -> Self {
+        let mut offsets = Vec::with_capacity(sizes.len());
+        let mut total = 0;
+        for size in sizes.iter() {
+            offsets.push(total);
+            total += size;
+        }
+        PairCoverage {
+            sizes,
+            offsets,
+            total,
+            before: vec![false; total * total],
+        }
+    }
+
+    fn pos(&self, (i, idx): (usize, usize)) -> usize {
+        self.offsets[i] + idx
+    }
+
+    /// 是否出现过`x`在`y`之前，`x`及`y`为(队列索引, 队列中的位置)
+    pub fn is_covered(&self, x: (usize, usize), y: (usize, usize)) -> bool {
+        self.before[self.pos(x) * self.total + self.pos(y)]
+    }
+
+    /// 记录交错排列中不同队列的元素两两之间的先后顺序
+    pub fn record(&mut self, batch: &[(usize, usize)]) {
+        for (depth, &y) in batch.iter().enumerate() {
+            for &x in batch[..depth].iter().filter(|x| x.0 != y.0) {
+                let (x, y) = (self.pos(x), self.pos(y));
+                self.before[x * self.total + y] = true;
+            }
+        }
+    }
+
+    /// 把`y`追加到`prefix`后，新出现的先后顺序的数量
+    fn gain(&self, prefix: &[(usize, usize)], y: (usize, usize)) -> usize {
+        prefix
+            .iter()
+            .filter(|x| x.0 != y.0 && !self.is_covered(**x, y))
+            .count()
+    }
+
+    /// 不同队列的元素之间所有先后顺序的数量，每对元素有两种顺序
+    pub fn pair_amount(&self) -> usize {
+        let same_queue: usize = self.sizes.iter().map(|size| size * size).sum();
+        self.total * self.total - same_queue
+    }
+
+    /// 已经出现过的先后顺序的数量
+    pub fn covered_amount(&self) -> usize {
+        self.before.iter().filter(|&&covered| covered).count()
+    }
+
+    pub fn sizes(&self) -> &[usize] {
+        &self.sizes
+    }
+}
+
+/// 生成较少的交错排列，使不同队列的元素两两之间都出现过两种先后顺序
+///
+/// 每次贪心地构造一个交错排列：每一步在剪枝条件允许的队列中，选择
+/// 追加后新出现的先后顺序最多的，走不通时回溯。构造出的交错排列没有
+/// 新出现的先后顺序时结束，剩下的先后顺序在剪枝条件下无法出现，或者
+/// 贪心没能找到
+pub struct PairwiseInterlacePermutations {
+    sizes: Vec<usize>,
+    pruners: Vec<Pruner>,
+    /// 最多的切换次数，为None时不限制
+    max_switches: Option<usize>,
+    coverage: PairCoverage,
+    /// 在剪枝条件下无法构造出的先后顺序
+    infeasible: HashSet<((usize, usize), (usize, usize))>,
+    finished: bool,
+}
+
+impl PairwiseInterlacePermutations {
+    /// # Arguments
+    ///
+    /// * `sizes` -  每个队列的大小
+    /// * `pruners` - 生成的交错排列都要满足的剪枝条件
+    /// * `max_switches` - 最多的切换次数，为None时不限制
+    pub fn new(sizes: Vec<usize>, pruners: Vec<Pruner>, max_switches: Option<usize>) -> Self {
+        PairwiseInterlacePermutations {
+            coverage: PairCoverage::new(sizes.clone()),
+            sizes,
+            pruners,
+            max_switches,
+            infeasible: HashSet::new(),
+            finished: false,
+        }
+    }
+
+    /// 已经生成的交错排列覆盖的先后顺序
+    pub fn into_coverage(self) -> PairCoverage {
+        self.coverage
+    }
+
+    /// 追加`x`后，它与还没追加的元素之间还可能新出现的先后顺序的数量
+    fn potential(&self, curs: &[usize], x: (usize, usize)) -> usize {
+        (0..self.sizes.len())
+            .filter(|&j| j != x.0)
+            .flat_map(|j| (curs[j]..self.sizes[j]).map(move |idx| (j, idx)))
+            .filter(|&y| !self.coverage.is_covered(x, y))
+            .count()
+    }
+
+    /// 当前可以追加的队列，新出现的先后顺序最多的放在最后，数量相同
+    /// 时按之后还可能新出现的数量，最后按队列索引从小到大
+    fn candidates(&self, result: &[(usize, usize)], curs: &[usize]) -> Vec<usize> {
+        let mut candidates: Vec<(usize, usize, usize)> = (0..self.sizes.len())
+            .filter(|&i| {
+                curs[i] < self.sizes[i] && self.pruners.iter().all(|pruner| pruner(result, curs, i))
+            })
+            .map(|i| {
+                let x = (i, curs[i]);
+                (self.coverage.gain(result, x), self.potential(curs, x), i)
+            })
+            .collect();
+        candidates.sort_by(|a, b| (a.0, a.1, b.2).cmp(&(b.0, b.1, a.2)));
+        candidates.into_iter().map(|(_, _, i)| i).collect()
+    }
+
+    /// 贪心地构造一个满足剪枝条件的交错排列，不存在或者尝试的步数
+    /// 超过限制时返回None
+    ///
+    /// # Arguments
+    ///
+    /// * `order` - 要求`x`在`y`之前出现时为Some((x, y))
+    fn build(
+        &self,
+        order: Option<((usize, usize), (usize, usize))>,
+    ) -> Option<Vec<(usize, usize)>> {
+        let total: usize = self.sizes.iter().sum();
+        let mut budget = PAIRWISE_BUILD_BUDGET * total.max(1);
+        let mut result = Vec::with_capacity(total);
+        let mut curs = vec![0; self.sizes.len()];
+        // 当前交错的切换次数，随着追加及回溯更新
+        let mut switches = 0;
+        let candidates = |result: &[(usize, usize)], curs: &[usize], switches: usize| {
+            let mut candidates = self.candidates(result, curs);
+            // 切换次数已经达到上限时，只能追加上一个队列
+            if self
+                .max_switches
+                .is_some_and(|max_switches| switches >= max_switches)
+            {
+                if let Some(&(last, _)) = result.last() {
+                    candidates.retain(|&i| i == last);
+                }
+            }
+            if let Some((x, y)) = order {
+                // `x`还没出现时不能追加`y`
+                if curs[x.0] <= x.1 {
+                    candidates.retain(|&i| (i, curs[i]) != y);
+                }
+            }
+            candidates
+        };
+        // 每一层还没尝试的队列，代替递归的栈
+        let mut stack = vec![candidates(&result, &curs, switches)];
+        while result.len() < total {
+            budget = budget.checked_sub(1)?;
+            match stack.last_mut()?.pop() {
+                Some(i) => {
+                    if result.last().is_some_and(|&(last, _)| last != i) {
+                        switches += 1;
+                    }
+                    result.push((i, curs[i]));
+                    curs[i] += 1;
+                    stack.push(candidates(&result, &curs, switches));
+                }
+                None => {
+                    // 该层已经尝试完，回溯到上一层
+                    stack.pop();
+                    let (i, _) = result.pop()?;
+                    curs[i] -= 1;
+                    if result.last().is_some_and(|&(last, _)| last != i) {
+                        switches -= 1;
+                    }
+                }
+            }
+        }
+        Some(result)
+    }
+
+    /// 还没出现过的先后顺序
+    fn uncovered(&self) -> Vec<((usize, usize), (usize, usize))> {
+        let steps: Vec<(usize, usize)> = self
+            .sizes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &size)| (0..size).map(move |idx| (i, idx)))
+            .collect();
+        let mut uncovered = vec![];
+        for &x in steps.iter() {
+            for &y in steps.iter() {
+                if x.0 != y.0 && !self.coverage.is_covered(x, y) {
+                    uncovered.push((x, y));
+                }
+            }
+        }
+        uncovered
+    }
+}
+
+impl Iterator for PairwiseInterlacePermutations {
+    type Item = Vec<(usize, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let covered = self.coverage.covered_amount();
+        if let Some(batch) = self.build(None) {
+            self.coverage.record(&batch);
+            if self.coverage.covered_amount() > covered {
+                return Some(batch);
+            }
+        }
+        // 贪心构造的交错排列没有新出现的先后顺序时，逐个尝试还没出现
+        // 过的先后顺序，都无法构造时结束
+        for order in self.uncovered() {
+            if self.infeasible.contains(&order) {
+                continue;
+            }
+            match self.build(Some(order)) {
+                Some(batch) => {
+                    self.coverage.record(&batch);
+                    return Some(batch);
+                }
+                None => {
+                    self.infeasible.insert(order);
+                }
+            }
+        }
+        self.finished = true;
+        None
+    }
+}
+
+/// 计算交错排列的数量，即多项式系数(n1+...+nk)!/(n1!...nk!)
+///
+/// # Arguments
+///
+/// * `sizes` -  每个队列的大小
+pub fn interlace_count(sizes: &[usize]) -> BigUint {
+    let mut count = BigUint::one();
+    let mut total = 0;
+    for &size in sizes {
+        // 依次乘上组合数C(total + size, size)，每一步都能整除
+        for j in 1..=size {
+            count = count * (total + j) / j;
+        }
+        total += size;
+    }
+    count
+}
+
+/// 生成所有串行顺序时最多的队列数量，k个队列有k!种串行顺序
+pub const MAX_SERIAL_QUEUES: usize = 8;
+
+/// 每个队列的元素都连续出现的交错排列，即队列之间的所有串行顺序，
+/// 共k!种，按队列顺序的字典序排列。队列数量超过`MAX_SERIAL_QUEUES`
+/// 时报错
+///
+/// # Arguments
+///
+/// * `sizes` -  每个队列的大小
+pub fn serial_permutations(sizes: &[usize]) -> Result<Vec<Vec<(usize, usize)>>> {
+    if sizes.len() > MAX_SERIAL_QUEUES {
+        return Err(Error::msg(format!(
+            "Serial orders of {} queues are too many, at most {} queues are supported",
+            sizes.len(),
+            MAX_SERIAL_QUEUES
+        )));
+    }
+    let mut orders: Vec<Vec<usize>> = vec![vec![]];
+    for _ in 0..sizes.len() {
+        let mut next = Vec::with_capacity(orders.len() * sizes.len());
+        for order in orders.iter() {
+            for i in (0..sizes.len()).filter(|i| !order.contains(i)) {
+                let mut order = order.clone();
+                order.push(i);
+                next.push(order);
+            }
+        }
+        orders = next;
+    }
+    Ok(orders
+        .into_iter()
+        .map(|order| {
+            order
+                .into_iter()
+                .flat_map(|i| (0..sizes[i]).map(move |j| (i, j)))
+                .collect()
+        })
+        .collect())
+}
+
+/// 从所有交错排列中均匀地随机抽取，不需要枚举所有排列
+///
+/// 把每个队列的索引按队列大小重复放到一个列表，对列表做随机洗牌，
+/// 每种不同的交错出现的概率相同。相同的种子会得到相同的抽样结果
+pub struct RandomInterlacePermutations {
+    /// 每个队列的索引按队列大小重复
+    queue_idxs: Vec<usize>,
+    queue_amount: usize,
+    rng: StdRng,
+    /// 剩余要抽取的数量
+    remaining: usize,
+    /// 不允许重复时，记录已经抽取过的交错排列
+    seen: Option<HashSet<Vec<usize>>>,
+}
+
+impl RandomInterlacePermutations {
+    /// # Arguments
+    ///
+    /// * `sizes` -  每个队列的大小
+    /// * `amount` - 抽取的数量，不允许重复时，最多抽取所有的交错排列
+    /// * `seed` - 随机数种子
+    /// * `allow_duplicates` - 是否允许抽到重复的交错排列
+    pub fn new(sizes: &[usize], amount: usize, seed: u64, allow_duplicates: bool) -> Self {
+        let mut queue_idxs = vec![];
+        for (i, &size) in sizes.iter().enumerate() {
+            queue_idxs.resize(queue_idxs.len() + size, i);
+        }
+        let mut remaining = amount;
+        let mut seen = None;
+        if !allow_duplicates {
+            let count = interlace_count(sizes).to_usize().unwrap_or(usize::MAX);
+            remaining = remaining.min(count);
+            seen = Some(HashSet::new());
+        }
+        RandomInterlacePermutations {
+            queue_idxs,
+            queue_amount: sizes.len(),
+            rng: StdRng::seed_from_u64(seed),
+            remaining,
+            seen,
+        }
+    }
+}
+
+impl Iterator for RandomInterlacePermutations {
+    type Item = Vec<(usize, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        loop {
+            self.queue_idxs.shuffle(&mut self.rng);
+            if let Some(seen) = &mut self.seen {
+                // 抽到重复的交错时重新抽取
+                if !seen.insert(self.queue_idxs.clone()) {
+                    continue;
+                }
+            }
+            break;
+        }
+        // 把队列索引的列表转换为(队列索引, 队列中的位置)
+        let mut curs = vec![0; self.queue_amount];
+        Some(
+            self.queue_idxs
+                .iter()
+                .map(|&i| {
+                    curs[i] += 1;
+                    (i, curs[i] - 1)
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Stream for RandomInterlacePermutations {
+    type Item = Vec<(usize, usize)>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.get_mut().next())
+    }
+}
+
+/// 计算交错排列在所有交错排列的字典序中的索引，从0开始
+///
+/// # Arguments
+///
+/// * `sizes` -  每个队列的大小
+/// * `batch` - 交错排列
+pub fn interlace_rank(sizes: &[usize], batch: &[(usize, usize)]) -> BigUint {
+    let mut remains = sizes.to_vec();
+    let mut total: usize = sizes.iter().sum();
+    // 剩余队列的交错排列数量
+    let mut count = interlace_count(sizes);
+    let mut rank = BigUint::from(0u8);
+    for &(i, _) in batch {
+        // 加上以更小的队列开头的交错排列的数量
+        for &remain in remains.iter().take(i) {
+            rank += &count * remain / total;
+        }
+        count = count * remains[i] / total;
+        remains[i] -= 1;
+        total -= 1;
+    }
+    rank
+}
+
+/// 根据字典序中的索引得到交错排列，是`interlace_rank`的逆运算
 ///
 /// # Arguments
 ///
-/// * `curs` -  每个队列当前位置
 /// * `sizes` -  每个队列的大小
-/// * `result` - 存放当前交错的结果
-/// * `then` - 处理结果
-pub fn interlace_permutation<'a, F, Fut>(
-    curs: &'a mut Vec<usize>,
-    sizes: &'a Vec<usize>,
-    result: &'a mut Vec<(usize, usize)>,
-    then: &'a F,
-) -> BoxFuture<'a, Result<()>>
-where
-    F: Fn(Vec<(usize, usize)>) -> Fut + Send + Sync,
-    Fut: Future<Output = Result<()>> + Send,
-{
-    async move {
+/// * `rank` - 交错排列的索引，从0开始
+pub fn interlace_unrank(sizes: &[usize], rank: &BigUint) -> Result<Vec<(usize, usize)>> {
+    let mut count = interlace_count(sizes);
+    if rank >= &count {
+        return Err(Error::msg(format!(
+            "Rank {} is out of the range of {} interlace permutations",
+            rank, count
+        )));
+    }
+    let mut rank = rank.clone();
+    let mut curs = vec![0; sizes.len()];
+    let mut total: usize = sizes.iter().sum();
+    let mut result = Vec::with_capacity(total);
+    while total > 0 {
         for i in 0..sizes.len() {
-            // 保存最开始的位置
-            let size = sizes[i];
-            if curs[i] < size {
-                // 把当前队列的索引及当前队列的位置放到结果里
+            let remain = sizes[i] - curs[i];
+            // 以队列i开头的交错排列的数量
+            let sub_count = &count * remain / total;
+            if rank < sub_count {
                 result.push((i, curs[i]));
                 curs[i] += 1;
-                interlace_permutation(curs, sizes, result, then).await?;
-                // 还原
-                curs[i] -= 1;
-                result.pop();
+                count = sub_count;
+                break;
             }
+            rank -= sub_count;
         }
-        if !curs
-            .iter()
-            .zip(sizes.iter())
-            .any(|(&cur, &size)| cur < size)
-        {
-            // 所有cur都不小于size，得到结果。
-            // 复制结果，并交由回调函数处理
-            return then(result.clone()).await;
+        total -= 1;
+    }
+    Ok(result)
+}
+
+/// 把所有交错排列按字典序平均分成`n`份，返回第`i`份的索引范围
+/// [start, end)
+///
+/// # Arguments
+///
+/// * `sizes` -  每个队列的大小
+/// * `i` - 分片的编号，从1开始
+/// * `n` - 分片的数量
+pub fn shard_range(sizes: &[usize], i: usize, n: usize) -> Result<(BigUint, BigUint)> {
+    if i == 0 || i > n {
+        return Err(Error::msg(format!(
+            "Shard {}/{} is out of the range of {} shards",
+            i, n, n
+        )));
+    }
+    let count = interlace_count(sizes);
+    Ok((&count * (i - 1) / n, &count * i / n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_matches_interlace_count() {
+        let cases = [
+            vec![1, 1],
+            vec![2, 3],
+            vec![3, 3],
+            vec![1, 2, 3],
+            vec![2, 2, 2],
+        ];
+        for sizes in cases.iter() {
+            let permutations: Vec<_> = InterlacePermutations::new(sizes.clone()).collect();
+            assert_eq!(
+                BigUint::from(permutations.len()),
+                interlace_count(sizes),
+                "sizes: {:?}",
+                sizes
+            );
+            // 按字典序生成，没有重复
+            assert!(permutations.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn test_empty_files() {
+        for sizes in [vec![], vec![0], vec![0, 0]].iter() {
+            let permutations: Vec<_> = InterlacePermutations::new(sizes.clone()).collect();
+            assert_eq!(permutations, vec![vec![]], "sizes: {:?}", sizes);
+            assert_eq!(interlace_count(sizes), BigUint::one());
+        }
+        let permutations: Vec<_> = InterlacePermutations::new(vec![0, 2, 0]).collect();
+        assert_eq!(permutations, vec![vec![(1, 0), (1, 1)]]);
+    }
+
+    #[test]
+    fn test_single_file() {
+        let permutations: Vec<_> = InterlacePermutations::new(vec![3]).collect();
+        assert_eq!(permutations, vec![vec![(0, 0), (0, 1), (0, 2)]]);
+    }
+
+    #[test]
+    fn test_rank_unrank() {
+        let cases = [
+            vec![1],
+            vec![2, 1],
+            vec![2, 3],
+            vec![1, 2, 2],
+            vec![2, 2, 2],
+        ];
+        for sizes in cases.iter() {
+            for (rank, batch) in InterlacePermutations::new(sizes.clone()).enumerate() {
+                let rank = BigUint::from(rank);
+                assert_eq!(interlace_rank(sizes, &batch), rank);
+                assert_eq!(interlace_unrank(sizes, &rank).unwrap(), batch);
+            }
+            assert!(interlace_unrank(sizes, &interlace_count(sizes)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_shards_cover_every_rank_once() {
+        let sizes = [2, 2, 1];
+        let count = interlace_count(&sizes).to_usize().unwrap();
+        for n in 1..=count + 2 {
+            let mut covered = vec![0; count];
+            for i in 1..=n {
+                let (start, end) = shard_range(&sizes, i, n).unwrap();
+                let (start, end) = (start.to_usize().unwrap(), end.to_usize().unwrap());
+                for times in covered[start..end].iter_mut() {
+                    *times += 1;
+                }
+            }
+            assert!(covered.iter().all(|&times| times == 1), "n: {}", n);
+        }
+        assert!(shard_range(&sizes, 0, 2).is_err());
+        assert!(shard_range(&sizes, 3, 2).is_err());
+    }
+
+    /// 第2个队列的第1个元素只能在第1个队列的前2个元素之后
+    fn after_two() -> Pruner {
+        Arc::new(|_, curs, i| i != 1 || curs[1] != 0 || curs[0] >= 2)
+    }
+
+    #[test]
+    fn test_max_switches() {
+        for sizes in [vec![2, 3], vec![1, 2, 2], vec![2, 2, 2]].iter() {
+            let all: Vec<_> = InterlacePermutations::new(sizes.clone()).collect();
+            let most = all.iter().map(|batch| switches(batch)).max().unwrap();
+            for max_switches in 0..=most + 1 {
+                let bounded: Vec<_> = InterlacePermutations::new(sizes.clone())
+                    .with_max_switches(max_switches)
+                    .collect();
+                let expected: Vec<_> = all
+                    .iter()
+                    .filter(|batch| switches(batch) <= max_switches)
+                    .cloned()
+                    .collect();
+                assert_eq!(
+                    bounded, expected,
+                    "sizes: {:?}, max: {}",
+                    sizes, max_switches
+                );
+            }
+        }
+        // 与剪枝条件一起使用
+        let bounded: Vec<_> = InterlacePermutations::new(vec![3, 2])
+            .with_pruner(after_two())
+            .with_max_switches(2)
+            .collect();
+        let expected = vec![
+            vec![(0, 0), (0, 1), (0, 2), (1, 0), (1, 1)],
+            vec![(0, 0), (0, 1), (1, 0), (1, 1), (0, 2)],
+        ];
+        assert_eq!(bounded, expected);
+    }
+
+    #[test]
+    fn test_fewest_switches_first() {
+        let cases = [
+            vec![3],
+            vec![2, 3],
+            vec![0, 2, 1],
+            vec![1, 2, 2],
+            vec![2, 2, 2],
+        ];
+        for sizes in cases.iter() {
+            let all: Vec<_> = InterlacePermutations::new(sizes.clone()).collect();
+            let most = all.iter().map(|batch| switches(batch)).max().unwrap();
+            for max_switches in (0..=most + 1).map(Some).chain(std::iter::once(None)) {
+                let ordered: Vec<_> =
+                    FewestSwitchesFirst::new(sizes.clone(), max_switches, vec![]).collect();
+                assert!(
+                    ordered
+                        .windows(2)
+                        .all(|w| switches(&w[0]) <= switches(&w[1])),
+                    "sizes: {:?}, max: {:?}",
+                    sizes,
+                    max_switches
+                );
+                // 与有上限的枚举是同一个集合
+                let mut sorted = ordered.clone();
+                sorted.sort();
+                let expected: Vec<_> = all
+                    .iter()
+                    .filter(|batch| max_switches.is_none_or(|max| switches(batch) <= max))
+                    .cloned()
+                    .collect();
+                assert_eq!(
+                    sorted, expected,
+                    "sizes: {:?}, max: {:?}",
+                    sizes, max_switches
+                );
+            }
+        }
+        let ordered: Vec<_> =
+            FewestSwitchesFirst::new(vec![3, 2], None, vec![after_two()]).collect();
+        let mut sorted = ordered.clone();
+        sorted.sort();
+        let expected: Vec<_> = InterlacePermutations::new(vec![3, 2])
+            .with_pruner(after_two())
+            .collect();
+        assert_eq!(sorted, expected);
+        assert!(ordered
+            .windows(2)
+            .all(|w| switches(&w[0]) <= switches(&w[1])));
+    }
+
+    #[test]
+    fn test_pairwise_covers_every_reachable_order() {
+        let cases: [(Vec<usize>, Vec<Pruner>, Option<usize>); 6] = [
+            (vec![2, 2], vec![], None),
+            (vec![3, 2, 2], vec![], None),
+            (vec![2, 2, 2], vec![], Some(2)),
+            (vec![3, 2], vec![after_two()], None),
+            (vec![3, 2, 1], vec![after_two()], Some(3)),
+            (vec![0, 3, 1], vec![], None),
+        ];
+        for (sizes, pruners, max_switches) in cases.iter() {
+            let mut all = InterlacePermutations::new(sizes.clone());
+            for pruner in pruners {
+                all = all.with_pruner(Arc::clone(pruner));
+            }
+            if let Some(max_switches) = *max_switches {
+                all = all.with_max_switches(max_switches);
+            }
+            let all: HashSet<_> = all.collect();
+            let mut reachable = PairCoverage::new(sizes.clone());
+            for batch in all.iter() {
+                reachable.record(batch);
+            }
+            let mut pairwise =
+                PairwiseInterlacePermutations::new(sizes.clone(), pruners.clone(), *max_switches);
+            let mut covered = PairCoverage::new(sizes.clone());
+            let mut amount = 0;
+            for batch in pairwise.by_ref() {
+                assert!(
+                    all.contains(&batch),
+                    "sizes: {:?}, batch: {:?}",
+                    sizes,
+                    batch
+                );
+                covered.record(&batch);
+                amount += 1;
+            }
+            assert!(amount <= all.len(), "sizes: {:?}", sizes);
+            assert_eq!(
+                covered.before, reachable.before,
+                "sizes: {:?}, max: {:?}",
+                sizes, max_switches
+            );
+            assert_eq!(pairwise.into_coverage().before, reachable.before);
+        }
+        // 两两之间的先后顺序比所有交错排列少得多
+        let amount = PairwiseInterlacePermutations::new(vec![3, 3, 3], vec![], None).count();
+        assert!(amount < 20, "amount: {}", amount);
+    }
+
+    #[test]
+    fn test_random_same_seed() {
+        let sizes = [3, 2, 3];
+        let sample = |seed| RandomInterlacePermutations::new(&sizes, 20, seed, false);
+        let first: Vec<_> = sample(7).collect();
+        assert_eq!(first.len(), 20);
+        assert_eq!(first, sample(7).collect::<Vec<_>>());
+        assert_ne!(first, sample(8).collect::<Vec<_>>());
+        // 允许重复时同样可以复现
+        let sample = |seed| RandomInterlacePermutations::new(&sizes, 20, seed, true);
+        assert_eq!(sample(7).collect::<Vec<_>>(), sample(7).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_random_duplicates() {
+        let sizes = [2, 1, 2];
+        let all: HashSet<_> = InterlacePermutations::new(sizes.to_vec()).collect();
+        for amount in [1, 10, all.len(), all.len() + 5].iter() {
+            let sample: Vec<_> =
+                RandomInterlacePermutations::new(&sizes, *amount, 1, false).collect();
+            // 不允许重复时最多抽取所有的交错排列
+            assert_eq!(sample.len(), (*amount).min(all.len()));
+            let distinct: HashSet<_> = sample.iter().cloned().collect();
+            assert_eq!(distinct.len(), sample.len(), "amount: {}", amount);
+            assert!(distinct.is_subset(&all));
         }
-        Ok(())
+        // 允许重复时抽取的数量可以超过所有交错排列的数量
+        let sample: Vec<_> = RandomInterlacePermutations::new(&sizes, 100, 1, true).collect();
+        assert_eq!(sample.len(), 100);
+        let distinct: HashSet<_> = sample.iter().cloned().collect();
+        assert!(distinct.len() < sample.len());
+        assert!(distinct.is_subset(&all));
     }
-    .boxed()
 }
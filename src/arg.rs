@@ -5,6 +5,37 @@ pub const CLUSTERS: &'static str = "clusters";
 pub const SQL_FILES: &'static str = "sql-files";
 pub const INIT_SQL_FILE: &'static str = "init-sql-file";
 pub const RESET_SQL_FILE: &'static str = "reset-sql-file";
+pub const CHECK_SQL_FILE: &'static str = "check-sql-file";
+pub const SESSION_PER_FILE: &'static str = "session-per-file";
+pub const BLOCK_THRESHOLD: &'static str = "block-threshold";
+pub const DRY_RUN: &'static str = "dry-run";
+pub const ESTIMATE: &'static str = "estimate";
+pub const SAMPLE: &'static str = "sample";
+pub const SEED: &'static str = "seed";
+pub const ALLOW_DUPLICATES: &'static str = "allow-duplicates";
+pub const SHARD: &'static str = "shard";
+pub const RANGE: &'static str = "range";
+pub const CHECKPOINT: &'static str = "checkpoint";
+pub const RESUME: &'static str = "resume";
+pub const POR: &'static str = "por";
+pub const COMMUTE: &'static str = "commute";
+pub const HAPPENS_BEFORE: &'static str = "happens-before";
+pub const MAX_SWITCHES: &'static str = "max-switches";
+pub const FEWEST_SWITCHES_FIRST: &'static str = "fewest-switches-first";
+pub const SHARE_PREFIX: &'static str = "share-prefix";
+pub const PAIRWISE: &'static str = "pairwise";
+pub const SYMMETRY: &'static str = "symmetry";
+pub const MATRIX: &'static str = "matrix";
+pub const CAPTURE: &'static str = "capture";
+pub const SERIALIZABLE: &'static str = "serializable";
+pub const COMPARE: &'static str = "compare";
+pub const RUN: &'static str = "run";
+pub const SCENARIO: &'static str = "scenario";
+pub const SUITE: &'static str = "suite";
+pub const SUITE_DIR: &'static str = "suite-dir";
+pub const IMPORT: &'static str = "import";
+pub const GENERAL_LOG: &'static str = "general-log";
+pub const OUTPUT_DIR: &'static str = "output-dir";
 
 pub const TIDB_DOCKER_COMPOSE_DIR: &'static str = "tidb-docker-compose-dir";
 
@@ -14,7 +45,7 @@ pub fn clusters<'a, 'b>() -> Arg<'a, 'b> {
         .help("Specify the urls for connecting to each database cluster. Example: `-c test:test@127.0.0.1:3306 test:test@127.0.0.1:3307`.")
         .multiple(true)
         .takes_value(true)
-        .required(true)
+        .required_unless(DRY_RUN)
 }
 
 /// 为了减少参数输入，MySQL的url scheme可以不填，在本函数补存
@@ -25,15 +56,20 @@ pub fn clusters<'a, 'b>() -> Arg<'a, 'b> {
 pub fn normalize_db_urls<'a>(matches: &ArgMatches<'a>) -> Vec<String> {
     let mut result = vec![];
     for url in matches.values_of(CLUSTERS).unwrap() {
-        if !url.starts_with("mysql://") {
-            result.push("mysql://".to_owned() + url);
-        } else {
-            result.push(String::from(url));
-        }
+        result.push(normalize_db_url(url));
     }
     result
 }
 
+/// 补充单个url的MySQL scheme
+pub fn normalize_db_url(url: &str) -> String {
+    if !url.starts_with("mysql://") {
+        "mysql://".to_owned() + url
+    } else {
+        String::from(url)
+    }
+}
+
 pub fn sql_files<'a, 'b>() -> Arg<'a, 'b> {
     Arg::with_name(SQL_FILES)
         .short("s")
@@ -59,6 +95,224 @@ pub fn reset_sql_file<'a, 'b>() -> Arg<'a, 'b> {
         .required(true)
 }
 
+pub fn check_sql_file<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(CHECK_SQL_FILE)
+        .short("k")
+        .help("Specify the sql file whose queries are executed after each batch, before the next reset. Their results are hashed as the final state, and batches are grouped by final state in the statistics.")
+        .takes_value(true)
+}
+
+pub fn session_per_file<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(SESSION_PER_FILE)
+        .long(SESSION_PER_FILE)
+        .help("Execute each sql file on its own database session, so that statements from different files run in different transactions.")
+}
+
+pub fn block_threshold<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(BLOCK_THRESHOLD)
+        .long(BLOCK_THRESHOLD)
+        .help("Specify how many milliseconds a sql can run before its session is considered blocked. Other sessions keep running while a session is blocked. Only used with --session-per-file.")
+        .takes_value(true)
+        .default_value("500")
+}
+
+pub fn dry_run<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(DRY_RUN)
+        .long(DRY_RUN)
+        .help("Only print the number of interlace permutations without executing them.")
+}
+
+pub fn estimate<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(ESTIMATE)
+        .long(ESTIMATE)
+        .help("Execute one serial sample batch on each cluster and print the estimated time of executing all batches. Only used with --dry-run.")
+        .requires(DRY_RUN)
+        .requires(CLUSTERS)
+}
+
+pub fn sample<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(SAMPLE)
+        .long(SAMPLE)
+        .help("Execute N interlace permutations drawn uniformly at random instead of all of them.")
+        .takes_value(true)
+        .value_name("N")
+}
+
+pub fn seed<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(SEED)
+        .long(SEED)
+        .help("Specify the random seed used by --sample. A random one is used if it is not specified. The seed is printed in the statistics.")
+        .takes_value(true)
+        .value_name("S")
+        .requires(SAMPLE)
+}
+
+pub fn allow_duplicates<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(ALLOW_DUPLICATES)
+        .long(ALLOW_DUPLICATES)
+        .help("Allow --sample to draw the same interlace permutation more than once.")
+        .requires(SAMPLE)
+}
+
+pub fn shard<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(SHARD)
+        .long(SHARD)
+        .help("Only execute the i-th of n equal slices of all interlace permutations, i starts from 1. Example: `--shard 2/4`.")
+        .takes_value(true)
+        .value_name("i/n")
+        .conflicts_with_all(&[SAMPLE, RANGE])
+}
+
+pub fn range<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(RANGE)
+        .long(RANGE)
+        .help("Only execute interlace permutations whose lexicographic indexes are in [start, end), indexes start from 0. Example: `--range 1000..2000`.")
+        .takes_value(true)
+        .value_name("start..end")
+        .conflicts_with(SAMPLE)
+}
+
+pub fn checkpoint<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(CHECKPOINT)
+        .long(CHECKPOINT)
+        .help("Save the progress and statistics to the checkpoint file periodically and on exit. Ctrl-C stops the run after the running batches are finished.")
+        .takes_value(true)
+        .value_name("FILE")
+}
+
+pub fn resume<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(RESUME)
+        .long(RESUME)
+        .help("Resume from the checkpoint file and skip the batches already completed. All other arguments should be the same as the interrupted run. The checkpoint file is also used to save new progress unless --checkpoint is specified.")
+        .takes_value(true)
+        .value_name("FILE")
+}
+
+pub fn por<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(POR)
+        .long(POR)
+        .help("Enable partial-order reduction. Statements from different files commute if they only read the same tables or touch different tables, and only one interlace permutation of each equivalence class is executed. Statements inside explicit transactions never commute. Requires session-per-file, since transactions are inferred per file.")
+        .conflicts_with(SAMPLE)
+}
+
+pub fn commute<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(COMMUTE)
+        .long(COMMUTE)
+        .help("Declare that two statements from different files commute, in format `file:row=file:row`, both start from 1. The file can also be a session name of the scenario, and the row can also be a label given by `-- @label`. Example: `--commute 1:2=2:3`.")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("file:row=file:row")
+        .requires(POR)
+}
+
+pub fn happens_before<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(HAPPENS_BEFORE)
+        .long(HAPPENS_BEFORE)
+        .help("Only execute interlace permutations in which the first statement runs before the second one, in format `file:row<file:row`, both start from 1. The file can also be a session name of the scenario, and the row can also be a label given by `-- @label`. Example: `--happens-before '1:1<2:3'`.")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("file:row<file:row")
+        .conflicts_with(SAMPLE)
+}
+
+pub fn max_switches<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(MAX_SWITCHES)
+        .long(MAX_SWITCHES)
+        .help("Only execute interlace permutations with at most K switches between files. Most concurrency bugs only need a few switches to appear. Can not be used with por, since the executed permutation of an equivalence class may need more switches.")
+        .takes_value(true)
+        .value_name("K")
+        .conflicts_with(SAMPLE)
+        .conflicts_with(POR)
+}
+
+pub fn fewest_switches_first<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(FEWEST_SWITCHES_FIRST)
+        .long(FEWEST_SWITCHES_FIRST)
+        .help("Execute interlace permutations with fewer switches between files first, those with the same switches are executed in lexicographic order.")
+        .conflicts_with(SAMPLE)
+}
+
+pub fn share_prefix<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(SHARE_PREFIX)
+        .long(SHARE_PREFIX)
+        .help("Execute all batches of a worker in one transaction and share the common prefix of adjacent batches with SAVEPOINT/ROLLBACK TO, instead of resetting and replaying every batch. The sql files should not commit the transaction, e.g. by COMMIT or DDL.")
+        .conflicts_with(SESSION_PER_FILE)
+}
+
+pub fn pairwise<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(PAIRWISE)
+        .long(PAIRWISE)
+        .help("Only execute a small set of interlace permutations in which every pair of statements from different files is executed in both orders, and print the coverage of the orders in the statistics.")
+        .conflicts_with_all(&[SAMPLE, SHARD, RANGE, FEWEST_SWITCHES_FIRST])
+}
+
+pub fn symmetry<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(SYMMETRY)
+        .long(SYMMETRY)
+        .help("Enable symmetry reduction. Identical sql files are considered interchangeable, and interlace permutations that only swap identical files are executed only once. Symmetry reduction is ignored with --happens-before or --commute.")
+}
+
+pub fn capture<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(CAPTURE)
+        .long(CAPTURE)
+        .help("Capture the returned rows, affected rows, last insert id and warnings of every sql in each interlace permutation, and write them as one json line per batch to the `sql_permutation::capture` log.")
+}
+
+pub fn serializable<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(SERIALIZABLE)
+        .long(SERIALIZABLE)
+        .help("Execute every serial order of the sql files on every database cluster first, and report the interlace permutations whose final state(-k) and captured results(--capture) match none of the serial orders on the same cluster. Requires -k or --capture, and at most 8 sql files.")
+}
+
+pub fn compare<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(COMPARE)
+        .long(COMPARE)
+        .help("Execute every interlace permutation on every database cluster instead of splitting them between clusters, and report the interlace permutations whose results, errors or final states(-k) differ between clusters. Requires at least two clusters.")
+}
+
+pub fn matrix<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(MATRIX)
+        .long(MATRIX)
+        .help("Specify the values of a template variable, in format `name=value1,value2,...`. `${name}` in the sqls is replaced by each value, and each combination of the values of all template variables is executed as a separate run with its own statistics. Example: `--matrix id=1,2 --matrix amount=10,20`.")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("name=values")
+}
+
+pub fn scenario<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(SCENARIO)
+        .help("Specify the scenario file in TOML format, which holds the init and reset sql, the sessions with their sqls, session variables, constraints and expectations.")
+        .required(true)
+        .index(1)
+}
+
+pub fn suite_dir<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(SUITE_DIR)
+        .help("Specify the directory to discover scenario files(*.toml) recursively.")
+        .required(true)
+        .index(1)
+}
+
+pub fn general_log<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(GENERAL_LOG)
+        .long(GENERAL_LOG)
+        .help("Specify the general query log of MySQL, or the log of TiDB with general log enabled. `-` means stdin.")
+        .takes_value(true)
+        .required(true)
+}
+
+pub fn output_dir<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(OUTPUT_DIR)
+        .short("o")
+        .long(OUTPUT_DIR)
+        .help("Specify the directory to write one session file for each connection.")
+        .takes_value(true)
+        .default_value(".")
+}
+
 pub fn log_config_file<'a, 'b>() -> Arg<'a, 'b> {
     Arg::with_name(LOG_CONFIG_FILE)
         .short("l")
@@ -1,24 +1,93 @@
-#![feature(async_closure)]
-
 use anyhow::Result;
-use clap::{crate_version, App, ArgMatches};
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
 use mysql_async::Opts;
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
 use std::collections::HashSet;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
 use sql_permutation::{
-    algo, arg,
-    concurrent::{ThreadPool, Worker},
-    file, init_log,
+    algo::{
+        self, FewestSwitchesFirst, InterlacePermutations, PairwiseInterlacePermutations, Pruner,
+        RandomInterlacePermutations,
+    },
+    arg,
+    checkpoint::{Checkpoint, Generation, SqlPair},
+    commute::Independence,
+    concurrent::{Batch, SerialOutcomes, ThreadPool, Worker},
+    constraint, file, import, init_log,
+    scenario::Scenario,
+    symmetry,
 };
 
+/// 直接指定sql文件及使用场景文件时都可以使用的参数
+fn common_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        arg::log_config_file(),
+        arg::session_per_file(),
+        arg::block_threshold(),
+        arg::dry_run(),
+        arg::estimate(),
+        arg::sample(),
+        arg::seed(),
+        arg::allow_duplicates(),
+        arg::shard(),
+        arg::range(),
+        arg::por(),
+        arg::commute(),
+        arg::happens_before(),
+        arg::max_switches(),
+        arg::fewest_switches_first(),
+        arg::share_prefix(),
+        arg::pairwise(),
+        arg::symmetry(),
+        arg::matrix(),
+        arg::capture(),
+        arg::check_sql_file(),
+        arg::serializable(),
+        arg::compare(),
+    ]
+}
+
 fn parse_params<'a, 'b>(app: App<'a, 'b>) -> ArgMatches<'a> {
     let app = app
-        .arg(arg::log_config_file())
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(arg::clusters())
         .arg(arg::sql_files())
         .arg(arg::init_sql_file())
-        .arg(arg::reset_sql_file());
+        .arg(arg::reset_sql_file())
+        .arg(arg::checkpoint())
+        .arg(arg::resume())
+        .args(&common_args())
+        .subcommand(
+            SubCommand::with_name(arg::RUN)
+                .about("Execute the scenario described by a scenario file.")
+                .arg(arg::scenario())
+                // 场景文件中可以指定数据库
+                .arg(arg::clusters().required(false))
+                .arg(arg::checkpoint())
+                .arg(arg::resume())
+                .args(&common_args()),
+        )
+        .subcommand(
+            SubCommand::with_name(arg::SUITE)
+                .about("Execute every scenario file in a directory, and print a pass/fail table. Exit with nonzero code if any scenario failed.")
+                .arg(arg::suite_dir())
+                .arg(arg::clusters().required(false))
+                .args(&common_args()),
+        )
+        .subcommand(
+            SubCommand::with_name(arg::IMPORT)
+                .about("Import the sqls of each client connection in a general log into session files.")
+                .arg(arg::general_log())
+                .arg(arg::output_dir())
+                .arg(arg::log_config_file()),
+        );
     app.get_matches()
 }
 
@@ -41,45 +110,884 @@ fn into_mysql_opts(db_urls: Vec<String>) -> Result<Vec<Opts>> {
     Ok(result)
 }
 
-async fn run<'a>(matches: ArgMatches<'a>) -> Result<()> {
-    init_log(matches.value_of(arg::LOG_CONFIG_FILE))?;
-    // 读取初始化sql，重置sql，及交错执行的sql
-    let init_sqls = file::read_sqls(matches.value_of(arg::INIT_SQL_FILE).unwrap()).await?;
-    let reset_sqls = file::read_sqls(matches.value_of(arg::RESET_SQL_FILE).unwrap()).await?;
-    let mut sqls_list = vec![];
-    let mut sizes = vec![];
-    let mut curs = vec![];
-    for file in matches.values_of(arg::SQL_FILES).unwrap() {
-        let sqls = file::read_sqls_by_line(file).await?;
-        curs.push(0);
-        sizes.push(sqls.len());
-        sqls_list.push(sqls);
-    }
+/// 每个数据库创建一个Worker
+fn new_workers<'a>(matches: &ArgMatches<'a>, scenario: &Scenario) -> Result<Vec<Worker>> {
     // 解析mysql的参数
-    let mysql_opts = into_mysql_opts(arg::normalize_db_urls(&matches))?;
-    let mut thread_pool = ThreadPool::new(mysql_opts.len());
-    for mysql_opt in mysql_opts {
-        thread_pool.add_worker(Worker::new(&init_sqls, &reset_sqls, &sqls_list, mysql_opt));
+    if scenario.clusters.is_empty() {
+        return Err(anyhow::Error::msg(
+            "No database cluster is specified, please use -c or `clusters` in the scenario file!",
+        ));
     }
+    let mysql_opts = into_mysql_opts(scenario.clusters.clone())?;
+    let session_per_file = scenario.session_per_file;
+    let share_prefix = matches.is_present(arg::SHARE_PREFIX);
+    let capture = matches.is_present(arg::CAPTURE);
+    let block_threshold = Duration::from_millis(
+        matches
+            .value_of(arg::BLOCK_THRESHOLD)
+            .unwrap()
+            .parse()
+            .map_err(|_| anyhow::Error::msg("block-threshold must be number!"))?,
+    );
+    Ok(mysql_opts
+        .into_iter()
+        .map(|mysql_opt| {
+            Worker::new(
+                &scenario.init_sqls,
+                &scenario.reset_sqls,
+                &scenario.sqls_list,
+                mysql_opt,
+            )
+            .session_per_file(session_per_file)
+            .block_threshold(block_threshold)
+            .share_prefix(share_prefix)
+            .blocks_list(scenario.blocks_list.clone())
+            .session_variables(scenario.session_variables.clone())
+            .capture(capture)
+            .check_sqls(scenario.check_sqls.clone())
+        })
+        .collect())
+}
+
+/// 解析随机抽样的数量及种子，没有指定种子时使用从检查点恢复的
+/// 种子，都没有时随机生成一个
+fn parse_sample<'a>(
+    matches: &ArgMatches<'a>,
+    resumed_seed: Option<u64>,
+) -> Result<Option<(usize, u64)>> {
+    let amount = match matches.value_of(arg::SAMPLE) {
+        Some(amount) => amount
+            .parse()
+            .map_err(|_| anyhow::Error::msg("sample must be number!"))?,
+        None => return Ok(None),
+    };
+    let seed = match matches.value_of(arg::SEED) {
+        Some(seed) => seed
+            .parse()
+            .map_err(|_| anyhow::Error::msg("seed must be number!"))?,
+        None => resumed_seed.unwrap_or_else(rand::random),
+    };
+    Ok(Some((amount, seed)))
+}
+
+/// 解析`--shard i/n`或`--range start..end`，得到要执行的交错排列的
+/// 索引范围[start, end)
+fn parse_range<'a>(
+    matches: &ArgMatches<'a>,
+    sizes: &[usize],
+) -> Result<Option<(BigUint, BigUint)>> {
+    if let Some(shard) = matches.value_of(arg::SHARD) {
+        let invalid = || anyhow::Error::msg("shard must be in format `i/n` and 1 <= i <= n!");
+        let mut parts = shard.splitn(2, '/');
+        let i: usize = parts
+            .next()
+            .and_then(|i| i.parse().ok())
+            .ok_or_else(invalid)?;
+        let n: usize = parts
+            .next()
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(invalid)?;
+        return algo::shard_range(sizes, i, n)
+            .map(Some)
+            .map_err(|_| invalid());
+    }
+    if let Some(range) = matches.value_of(arg::RANGE) {
+        let invalid = || anyhow::Error::msg("range must be in format `start..end`!");
+        let mut parts = range.splitn(2, "..");
+        let start: BigUint = parts
+            .next()
+            .and_then(|start| start.parse().ok())
+            .ok_or_else(invalid)?;
+        let end: BigUint = parts
+            .next()
+            .and_then(|end| end.parse().ok())
+            .ok_or_else(invalid)?;
+        let count = algo::interlace_count(sizes);
+        return Ok(Some((start.min(count.clone()), end.min(count))));
+    }
+    Ok(None)
+}
+
+/// 是否需要剪枝，剪枝后要执行的交错排列数量只能通过枚举得到
+///
+/// # Arguments
+///
+/// * `symmetric` - 是否有内容相同的文件需要做对称归约
+fn is_pruned<'a>(matches: &ArgMatches<'a>, scenario: &Scenario, symmetric: bool) -> bool {
+    symmetric
+        || matches.is_present(arg::POR)
+        || !scenario.constraints.is_empty()
+        || matches.is_present(arg::MAX_SWITCHES)
+}
+
+/// 指定了`--symmetry`时，找出需要做对称归约的相同文件。先后约束及
+/// 声明可交换的sql指定了具体的文件，互换相同文件后不再等价，此时
+/// 不做对称归约
+fn parse_symmetry<'a>(matches: &ArgMatches<'a>, scenario: &Scenario) -> Vec<Vec<usize>> {
+    if !matches.is_present(arg::SYMMETRY)
+        || !scenario.constraints.is_empty()
+        || matches.is_present(arg::COMMUTE)
+    {
+        return vec![];
+    }
+    symmetry::identical_groups(
+        &scenario.sqls_list,
+        &scenario.blocks_list,
+        &scenario.session_variables,
+    )
+}
+
+fn parse_max_switches<'a>(matches: &ArgMatches<'a>) -> Result<Option<usize>> {
+    match matches.value_of(arg::MAX_SWITCHES) {
+        Some(max_switches) => Ok(Some(
+            max_switches
+                .parse()
+                .map_err(|_| anyhow::Error::msg("max-switches must be number!"))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// 解析声明可交换的sql
+fn parse_commutes<'a>(matches: &ArgMatches<'a>, scenario: &Scenario) -> Result<Vec<SqlPair>> {
+    let mut commutes = vec![];
+    for commute in matches.values_of(arg::COMMUTE).into_iter().flatten() {
+        let invalid = || anyhow::Error::msg("commute must be in format `file:row=file:row`!");
+        let mut parts = commute.splitn(2, '=');
+        let a = scenario.resolve(parts.next().ok_or_else(invalid)?)?;
+        let b = scenario.resolve(parts.next().ok_or_else(invalid)?)?;
+        commutes.push((a, b));
+    }
+    Ok(commutes)
+}
+
+/// 生成交错排列的选项，保存到检查点，恢复时检查是否一致
+fn parse_generation<'a>(
+    matches: &ArgMatches<'a>,
+    scenario: &Scenario,
+    sizes: &[usize],
+    sample: Option<(usize, u64)>,
+) -> Result<Generation> {
+    Ok(Generation {
+        sample: sample.map(|(amount, _)| amount),
+        seed: sample.map(|(_, seed)| seed),
+        allow_duplicates: matches.is_present(arg::ALLOW_DUPLICATES),
+        range: parse_range(matches, sizes)?
+            .map(|(start, end)| (start.to_string(), end.to_string())),
+        por: matches.is_present(arg::POR),
+        commute: parse_commutes(matches, scenario)?,
+        happens_before: scenario
+            .constraints
+            .iter()
+            .map(|constraint| (constraint.before, constraint.after))
+            .collect(),
+        max_switches: parse_max_switches(matches)?,
+        pairwise: matches.is_present(arg::PAIRWISE),
+        fewest_switches_first: matches.is_present(arg::FEWEST_SWITCHES_FIRST),
+        symmetry: parse_symmetry(matches, scenario),
+    })
+}
+
+/// 根据先后约束，及开启偏序归约时推断及声明的可交换sql，
+/// 生成剪枝条件
+fn parse_pruners<'a>(matches: &ArgMatches<'a>, scenario: &Scenario) -> Result<Vec<Pruner>> {
+    let mut pruners = vec![];
+    let constraints = &scenario.constraints;
+    if !constraints.is_empty() {
+        pruners.push(constraint::into_pruner(constraints, &scenario.blocks_list)?);
+    }
+    let groups = parse_symmetry(matches, scenario);
+    if !groups.is_empty() {
+        pruners.push(symmetry::into_pruner(&groups, scenario.sqls_list.len()));
+    }
+    if !matches.is_present(arg::POR) {
+        return Ok(pruners);
+    }
+    let mut independence = Independence::infer(&scenario.sqls_list);
+    for (a, b) in parse_commutes(matches, scenario)? {
+        independence.declare(a, b)?;
+    }
+    for constraint in constraints.iter() {
+        independence.depend(constraint.before, constraint.after);
+    }
+    pruners.push(independence.into_pruner(&scenario.blocks_list));
+    Ok(pruners)
+}
+
+/// 按字典序生成交错排列，指定了索引范围时只生成范围内的。指定了
+/// `--fewest-switches-first`时，按切换次数从少到多生成
+fn interlace_permutations<'a>(
+    matches: &ArgMatches<'a>,
+    sizes: &[usize],
+    range: Option<(BigUint, BigUint)>,
+    pruners: Vec<Pruner>,
+) -> Result<Batches> {
+    let max_switches = parse_max_switches(matches)?;
+    if matches.is_present(arg::FEWEST_SWITCHES_FIRST) {
+        let permutations = FewestSwitchesFirst::new(sizes.to_vec(), max_switches, pruners);
+        let (start, end) = match range {
+            Some(range) => range,
+            None => return Ok(Box::new(permutations)),
+        };
+        // 不按字典序生成时，只能逐个判断索引是否在范围内
+        let sizes = sizes.to_vec();
+        return Ok(Box::new(permutations.filter(move |batch| {
+            let rank = algo::interlace_rank(&sizes, batch);
+            start <= rank && rank < end
+        })));
+    }
+    let (start, end) = match range {
+        Some(range) => range,
+        None => {
+            let mut permutations = InterlacePermutations::new(sizes.to_vec());
+            if let Some(max_switches) = max_switches {
+                permutations = permutations.with_max_switches(max_switches);
+            }
+            for pruner in pruners {
+                permutations = permutations.with_pruner(pruner);
+            }
+            return Ok(Box::new(permutations));
+        }
+    };
+    if start >= end {
+        return Ok(Box::new(std::iter::empty()));
+    }
+    let mut permutations = InterlacePermutations::from_rank(sizes.to_vec(), &start)?;
+    if let Some(max_switches) = max_switches {
+        permutations = permutations.with_max_switches(max_switches);
+    }
+    for pruner in pruners {
+        permutations = permutations.with_pruner(pruner);
+    }
+    // 剪枝后范围内的交错排列数量未知，按索引判断是否超出范围
+    let sizes = sizes.to_vec();
+    Ok(Box::new(permutations.take_while(move |batch| {
+        algo::interlace_rank(&sizes, batch) < end
+    })))
+}
+
+/// 生成两两顺序覆盖的交错排列的生成器
+fn pairwise_permutations<'a>(
+    matches: &ArgMatches<'a>,
+    sizes: &[usize],
+    pruners: Vec<Pruner>,
+) -> Result<PairwiseInterlacePermutations> {
+    Ok(PairwiseInterlacePermutations::new(
+        sizes.to_vec(),
+        pruners,
+        parse_max_switches(matches)?,
+    ))
+}
+
+/// 把秒数格式化为便于阅读的时间，时间过长时使用科学计数法
+fn format_secs(secs: f64) -> String {
+    if secs.is_finite() && secs < 1e9 {
+        format!("{:?}", Duration::from_secs_f64(secs))
+    } else {
+        format!("{:.3e}s (about {:.3e} years)", secs, secs / 31_557_600.0)
+    }
+}
+
+/// 只打印交错排列的数量，指定了estimate时，在每个数据库串行执行
+/// 一个样例批次，按所有数据库的吞吐估算执行所有批次的时间
+async fn dry_run<'a>(matches: &ArgMatches<'a>, scenario: &Scenario) -> Result<()> {
+    // 每种取值组合的交错排列都相同，按第一种组合计算
+    let combinations = scenario.combinations();
+    let scenario = &scenario.instantiate(&combinations[0])?;
+    let sizes = &scenario.sizes()[..];
+    let mut count = algo::interlace_count(sizes);
+    println!("Total interlace permutations: {}", count);
+    if let Some((amount, seed)) = parse_sample(matches, None)? {
+        if matches.is_present(arg::ALLOW_DUPLICATES) || BigUint::from(amount) < count {
+            count = BigUint::from(amount);
+        }
+        println!("Sampled interlace permutations: {}, seed: {}", count, seed);
+    }
+    let range = parse_range(matches, sizes)?;
+    if let Some((start, end)) = &range {
+        count = if start < end { end - start } else { BigUint::from(0u8) };
+        println!(
+            "Interlace permutations in range [{}, {}): {}",
+            start, end, count
+        );
+    }
+    // 随机抽样时不做剪枝
+    let groups = if matches.is_present(arg::SAMPLE) {
+        vec![]
+    } else {
+        parse_symmetry(matches, scenario)
+    };
+    for group in groups.iter() {
+        let files: Vec<String> = group.iter().map(|i| (i + 1).to_string()).collect();
+        println!("Identical files: {}", files.join(", "));
+    }
+    if matches.is_present(arg::PAIRWISE) {
+        let pruners = parse_pruners(matches, scenario)?;
+        let mut permutations = pairwise_permutations(matches, sizes, pruners)?;
+        count = BigUint::from(permutations.by_ref().count());
+        let coverage = permutations.into_coverage();
+        println!(
+            "Pairwise interlace permutations: {}, covering {} of {} orders",
+            count,
+            coverage.covered_amount(),
+            coverage.pair_amount()
+        );
+    } else if is_pruned(matches, scenario, false) || (range.is_some() && !groups.is_empty()) {
+        // 剪枝后的数量只能通过枚举得到
+        let pruners = parse_pruners(matches, scenario)?;
+        let reduced = BigUint::from(interlace_permutations(matches, sizes, range, pruners)?.count());
+        println!(
+            "Interlace permutations after pruning: {}, pruned: {}",
+            reduced,
+            &count - &reduced
+        );
+        count = reduced;
+    } else if !groups.is_empty() {
+        // 只做对称归约时，每种交错排列互换相同文件后得到的交错排列都不相同
+        let reduced = &count / symmetry::symmetry_factor(&groups);
+        println!(
+            "Interlace permutations after symmetry reduction: {}, pruned: {}",
+            reduced,
+            &count - &reduced
+        );
+        count = reduced;
+    }
+    if combinations.len() > 1 {
+        count *= combinations.len();
+        println!(
+            "Value combinations of template variables: {}, interlace permutations of all combinations: {}",
+            combinations.len(),
+            count
+        );
+    }
+    if !matches.is_present(arg::ESTIMATE) {
+        return Ok(());
+    }
+    let workers = new_workers(matches, scenario)?;
+    // 每秒能执行的批次数
+    let mut throughput = 0.0;
+    for worker in workers.iter() {
+        let time = worker.time_sample_batch().await?;
+        println!(
+            "Sample batch time of database[{}]: {:?}",
+            worker.mysql_target(),
+            time
+        );
+        throughput += 1.0 / time.as_secs_f64();
+    }
+    let secs = count.to_f64().unwrap_or(f64::INFINITY) / throughput;
+    println!(
+        "Estimated time with {} worker(s): {}",
+        workers.len(),
+        format_secs(secs)
+    );
+    Ok(())
+}
+
+/// 要执行的交错排列
+type Batches = Box<dyn Iterator<Item = Vec<(usize, usize)>>>;
+
+/// 根据参数生成要执行的交错排列
+fn generate_batches<'a>(
+    matches: &ArgMatches<'a>,
+    scenario: &Scenario,
+    sizes: &[usize],
+    sample: Option<(usize, u64)>,
+    thread_pool: &mut ThreadPool,
+) -> Result<Batches> {
+    if let Some((amount, seed)) = sample {
+        log::info!("sample {} batches with seed: {}", amount, seed);
+        thread_pool.set_seed(seed);
+        let allow_duplicates = matches.is_present(arg::ALLOW_DUPLICATES);
+        return Ok(Box::new(RandomInterlacePermutations::new(
+            sizes,
+            amount,
+            seed,
+            allow_duplicates,
+        )));
+    }
+    let range = parse_range(matches, sizes)?;
+    if let Some((start, end)) = &range {
+        log::info!("execute batches in range [{}, {})", start, end);
+    }
+    if matches.is_present(arg::POR) {
+        log::info!("partial-order reduction is enabled");
+    }
+    for group in parse_symmetry(matches, scenario) {
+        log::info!("symmetry reduction for identical files: {:?}", group);
+    }
+    let pruners = parse_pruners(matches, scenario)?;
+    if matches.is_present(arg::PAIRWISE) {
+        // 覆盖的交错排列数量较少，直接全部生成
+        let batches: Vec<_> = pairwise_permutations(matches, sizes, pruners)?.collect();
+        log::info!("execute {} pairwise batches", batches.len());
+        return Ok(Box::new(batches.into_iter()));
+    }
+    if matches.is_present(arg::FEWEST_SWITCHES_FIRST) {
+        log::info!("execute batches with fewer switches first");
+    }
+    interlace_permutations(matches, sizes, range, pruners)
+}
+
+/// 把生成的所有交错排列展开为sql后提交到线程池，并带上它在字典序
+/// 中的索引，返回生成的交错排列数量
+async fn submit_all(
+    thread_pool: &ThreadPool,
+    blocks_list: &[Vec<Range<usize>>],
+    sizes: &[usize],
+    batches: impl Iterator<Item = Vec<(usize, usize)>>,
+) -> Result<BigUint> {
+    let mut amount = BigUint::from(0u8);
+    for batch in batches {
+        let idx = algo::interlace_rank(sizes, &batch);
+        let sql_idxs = file::expand_blocks(blocks_list, &batch);
+        thread_pool.submit(Batch::new(idx, sql_idxs)).await?;
+        amount += 1u8;
+    }
+    Ok(amount)
+}
 
-    let thread_pool_ref = &thread_pool;
-    let result =
-        algo::interlace_permutation(&mut curs, &sizes, &mut Vec::new(), &async move |result| {
-            thread_pool_ref.submit(result).await
+/// 读取要执行的场景，命令行参数中的数据库、独立会话及先后约束
+/// 会合并到场景中
+async fn load_scenario<'a>(
+    matches: &ArgMatches<'a>,
+    scenario_file: Option<&str>,
+) -> Result<Scenario> {
+    let mut scenario = match scenario_file {
+        Some(scenario_file) => Scenario::load(scenario_file).await?,
+        None => {
+            let sql_files: Vec<&str> = matches.values_of(arg::SQL_FILES).unwrap().collect();
+            Scenario::from_files(
+                matches.value_of(arg::INIT_SQL_FILE).unwrap(),
+                matches.value_of(arg::RESET_SQL_FILE).unwrap(),
+                &sql_files,
+            )
+            .await?
+        }
+    };
+    if matches.is_present(arg::CLUSTERS) {
+        scenario.clusters = arg::normalize_db_urls(matches);
+    } else {
+        scenario.clusters = scenario
+            .clusters
+            .iter()
+            .map(|url| arg::normalize_db_url(url))
+            .collect();
+    }
+    if let Some(check_sql_file) = matches.value_of(arg::CHECK_SQL_FILE) {
+        scenario.check_sqls = file::read_sql_file(check_sql_file).await?.sqls;
+    }
+    if matches.is_present(arg::SERIALIZABLE)
+        && scenario.check_sqls.is_empty()
+        && !matches.is_present(arg::CAPTURE)
+    {
+        return Err(anyhow::Error::msg(
+            "serializable requires check sqls(-k) or capture!",
+        ));
+    }
+    scenario.session_per_file |= matches.is_present(arg::SESSION_PER_FILE);
+    if !scenario.session_per_file && scenario.session_variables.iter().any(Option::is_some) {
+        return Err(anyhow::Error::msg(
+            "Session variables can only be used with session-per-file!",
+        ));
+    }
+    // 所有文件共用一个连接时，事务跨越文件，按文件推断的事务边界不成立
+    if !scenario.session_per_file && matches.is_present(arg::POR) {
+        return Err(anyhow::Error::msg(
+            "por can only be used with session-per-file!",
+        ));
+    }
+    if scenario.session_per_file && matches.is_present(arg::SHARE_PREFIX) {
+        return Err(anyhow::Error::msg(
+            "share-prefix can not be used with session-per-file!",
+        ));
+    }
+    for matrix in matches.values_of(arg::MATRIX).into_iter().flatten() {
+        let invalid = || {
+            anyhow::Error::msg(format!(
+                "matrix `{}` must be in format `name=value1,value2,...`!",
+                matrix
+            ))
+        };
+        let mut parts = matrix.splitn(2, '=');
+        let name = parts.next().ok_or_else(invalid)?.trim();
+        let values = parts.next().ok_or_else(invalid)?;
+        if name.is_empty() || values.trim().is_empty() {
+            return Err(invalid());
+        }
+        scenario.matrix.insert(
+            name.to_owned(),
+            values
+                .split(',')
+                .map(|value| value.trim().to_owned())
+                .collect(),
+        );
+    }
+    if matches.is_present(arg::COMPARE) {
+        if scenario.clusters.len() < 2 {
+            return Err(anyhow::Error::msg(
+                "compare requires at least two database clusters!",
+            ));
+        }
+        if matches.is_present(arg::CHECKPOINT) || matches.is_present(arg::RESUME) {
+            return Err(anyhow::Error::msg(
+                "checkpoint can not be used with compare!",
+            ));
+        }
+    }
+    let combinations = scenario.combinations().len();
+    if combinations > 1 && (matches.is_present(arg::CHECKPOINT) || matches.is_present(arg::RESUME))
+    {
+        return Err(anyhow::Error::msg(
+            "checkpoint can not be used with multiple value combinations of template variables!",
+        ));
+    }
+    for constraint in matches.values_of(arg::HAPPENS_BEFORE).into_iter().flatten() {
+        let constraint = scenario.parse_constraint(constraint)?;
+        scenario.constraints.push(constraint);
+    }
+    // 随机抽样不经过剪枝，场景文件中的先后约束会被忽略
+    if !scenario.constraints.is_empty() && matches.is_present(arg::SAMPLE) {
+        return Err(anyhow::Error::msg(
+            "sample can not be used with happens-before constraints!",
+        ));
+    }
+    Ok(scenario)
+}
+
+/// 在每个数据库上执行文件之间的所有串行顺序，返回每个数据库得到的
+/// 所有不同结果
+async fn run_serial_orders<'a>(
+    matches: &ArgMatches<'a>,
+    scenario: &Scenario,
+) -> Result<SerialOutcomes> {
+    let sizes = scenario.sizes();
+    let serial_orders = algo::serial_permutations(&sizes)?;
+    let workers = new_workers(matches, scenario)?;
+    let mut thread_pool = ThreadPool::new(workers.len());
+    // 不同数据库允许的结果可能不同，每个数据库都执行所有串行顺序
+    thread_pool.enable_broadcast(workers.len()).await;
+    for worker in workers {
+        thread_pool.add_worker(worker.record_outcomes(true));
+    }
+    let result = submit_all(
+        &thread_pool,
+        &scenario.blocks_list,
+        &sizes,
+        serial_orders.into_iter(),
+    )
+    .await;
+    thread_pool.shutdown().await;
+    thread_pool.join().await;
+    let (batch_amount, errors) = thread_pool.outcome().await;
+    if let Some((mysql_target, error)) = errors.first() {
+        return Err(anyhow::Error::msg(format!(
+            "Executing serial orders failed in database[{}]:\n{}",
+            mysql_target, error
+        )));
+    }
+    result?;
+    let outcomes = thread_pool.outcomes().await;
+    log::info!("serial orders executed: {}", batch_amount);
+    for (mysql_target, outcomes) in outcomes.iter() {
+        log::info!(
+            "distinct outcomes of serial orders in database[{}]: {}",
+            mysql_target,
+            outcomes.len()
+        );
+    }
+    Ok(outcomes)
+}
+
+/// 执行场景的交错排列，返回所有Worker结束后的线程池，用于获取
+/// 执行结果
+async fn execute<'a>(matches: &ArgMatches<'a>, scenario: &Scenario) -> Result<ThreadPool> {
+    // 每个文件的块数量，交错排列以块为单位
+    let sizes = scenario.sizes();
+    // 先执行所有串行顺序，得到可串行化的结果
+    let serial_outcomes = if matches.is_present(arg::SERIALIZABLE) {
+        Some(Arc::new(run_serial_orders(matches, scenario).await?))
+    } else {
+        None
+    };
+    let mut workers = new_workers(matches, scenario)?;
+    if let Some(serial_outcomes) = &serial_outcomes {
+        // 每个数据库只与自己串行执行的结果比较
+        workers = workers
+            .into_iter()
+            .map(|worker| {
+                let outcomes = serial_outcomes
+                    .iter()
+                    .find(|(mysql_target, _)| mysql_target == worker.mysql_target())
+                    .map(|(_, outcomes)| outcomes.clone())
+                    .unwrap_or_default();
+                worker.serial_outcomes(Arc::new(outcomes))
+            })
+            .collect();
+    }
+    if matches.is_present(arg::COMPARE) {
+        workers = workers
+            .into_iter()
+            .map(|worker| worker.compare(true))
+            .collect();
+    }
+    let mut thread_pool = ThreadPool::new(workers.len());
+    if matches.is_present(arg::COMPARE) {
+        thread_pool.enable_compare(workers.len()).await;
+    }
+    if matches.is_present(arg::SHARE_PREFIX) {
+        thread_pool.enable_share_prefix(workers.len()).await;
+    }
+    if !scenario.name.is_empty() {
+        thread_pool.set_scenario(scenario.name.clone());
+    }
+    thread_pool.set_check_sqls(scenario.check_sqls.clone());
+    if let Some(serial_outcomes) = serial_outcomes {
+        thread_pool.set_serial_outcomes(serial_outcomes);
+    }
+    if matches.is_present(arg::PAIRWISE) {
+        thread_pool
+            .enable_coverage(&scenario.sqls_list, &scenario.blocks_list)
+            .await;
+    }
+    let checkpoint = match matches.value_of(arg::RESUME) {
+        Some(resume) => Some(Checkpoint::load(resume)?),
+        None => None,
+    };
+    // 恢复时没有指定种子，使用检查点中的种子
+    let resumed_seed = checkpoint
+        .as_ref()
+        .and_then(|checkpoint| checkpoint.generation.seed);
+    let sample = parse_sample(matches, resumed_seed)?;
+    let generation = parse_generation(matches, scenario, &sizes, sample)?;
+    let batches = generate_batches(matches, scenario, &sizes, sample, &mut thread_pool)?;
+    // 开启检查点，恢复时默认继续保存到恢复的检查点文件
+    let checkpoint_file = matches
+        .value_of(arg::CHECKPOINT)
+        .or_else(|| matches.value_of(arg::RESUME));
+    if let Some(checkpoint_file) = checkpoint_file {
+        thread_pool
+            .enable_checkpoint(
+                PathBuf::from(checkpoint_file),
+                sizes.clone(),
+                generation.clone(),
+            )
+            .await;
+        thread_pool.interrupt_on_ctrl_c();
+    }
+    if let Some(checkpoint) = checkpoint {
+        let checkpoint = checkpoint.resume(&sizes, &generation)?;
+        thread_pool.resume(checkpoint).await?;
+    }
+    // 恢复检查点后再启动Worker，Worker启动时会取出恢复的统计数据
+    for worker in workers {
+        thread_pool.add_worker(worker);
+    }
+
+    // 不剪枝时要执行的交错排列数量，用于计算剪掉的数量
+    let symmetric = !parse_symmetry(matches, scenario).is_empty();
+    let unreduced_count = if is_pruned(matches, scenario, symmetric)
+        && !matches.is_present(arg::PAIRWISE)
+        && !matches.is_present(arg::SAMPLE)
+    {
+        Some(match parse_range(matches, &sizes)? {
+            Some((start, end)) if start < end => end - start,
+            Some(_) => BigUint::from(0u8),
+            None => algo::interlace_count(&sizes),
         })
-        .await;
-    if result.is_ok() {
+    } else {
+        None
+    };
+    let result = submit_all(&thread_pool, &scenario.blocks_list, &sizes, batches).await;
+    if let Ok(amount) = &result {
         // 成功处理完成，关闭线程池
         log::info!("interlace permutation finished.");
         thread_pool.shutdown().await;
+        if let Some(count) = unreduced_count {
+            thread_pool.set_pruned(count - amount);
+        }
     }
     log::info!("waiting all workers to be finished.");
     // 等待所有任务结束
     thread_pool.join().await;
+    if let Err(e) = thread_pool.save_checkpoint().await {
+        log::warn!("saving checkpoint failed:\n{}", e);
+    }
 
     // 打印统计信息及异常
-    thread_pool.print_statistic(&sqls_list).await;
+    thread_pool.print_statistic(&scenario.sqls_list).await;
+
+    Ok(thread_pool)
+}
+
+/// 依次执行模板变量的每种取值组合，每种组合使用独立的线程池，
+/// 统计数据分别输出。返回所有组合执行的批次总数，及每个执行出错
+/// 的数据库的错误
+async fn execute_matrix<'a>(
+    matches: &ArgMatches<'a>,
+    scenario: &Scenario,
+) -> Result<(usize, Vec<String>)> {
+    let mut batch_amount = 0;
+    let mut errors = vec![];
+    for values in scenario.combinations() {
+        let instance = scenario.instantiate(&values)?;
+        if !values.is_empty() {
+            log::info!("running with template variables: {:?}", values);
+        }
+        let (amount, instance_errors) = execute(matches, &instance).await?.outcome().await;
+        batch_amount += amount;
+        for (mysql_target, error) in instance_errors {
+            if values.is_empty() {
+                errors.push(format!("database[{}]: {}", mysql_target, error));
+            } else {
+                errors.push(format!(
+                    "database[{}] of {}: {}",
+                    mysql_target, instance.name, error
+                ));
+            }
+        }
+    }
+    Ok((batch_amount, errors))
+}
+
+/// 递归找出目录中的所有场景文件
+fn discover_scenarios(dir: &Path, scenario_files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            discover_scenarios(&path, scenario_files)?;
+        } else if path.extension().is_some_and(|ext| ext == "toml") {
+            scenario_files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// 依次执行目录中的每个场景，每个场景使用独立的线程池及数据库
+/// 连接，并在执行前重置及初始化数据库，最后打印每个场景是否通过。
+/// 场景无法读取，或者有数据库执行出错时，该场景不通过
+async fn run_suite<'a>(matches: &ArgMatches<'a>) -> Result<()> {
+    let dir = matches.value_of(arg::SUITE_DIR).unwrap();
+    let mut scenario_files = vec![];
+    discover_scenarios(Path::new(dir), &mut scenario_files)?;
+    scenario_files.sort();
+    if scenario_files.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "No scenario file is found in directory[{}]",
+            dir
+        )));
+    }
+    // 每个场景的(名字, 是否通过, 说明)
+    let mut results = vec![];
+    for scenario_file in scenario_files.iter() {
+        let name = scenario_file
+            .strip_prefix(dir)
+            .unwrap_or(scenario_file)
+            .to_string_lossy()
+            .into_owned();
+        log::info!("running scenario[{}]", name);
+        let scenario = match load_scenario(matches, scenario_file.to_str()).await {
+            Ok(scenario) => scenario,
+            Err(e) => {
+                results.push((name, false, format!("{}", e)));
+                continue;
+            }
+        };
+        if matches.is_present(arg::DRY_RUN) {
+            println!("Scenario: {}", name);
+            let result = dry_run(matches, &scenario).await;
+            results.push(match result {
+                Ok(_) => (name, true, String::new()),
+                Err(e) => (name, false, format!("{}", e)),
+            });
+            continue;
+        }
+        let (batch_amount, errors) = match execute_matrix(matches, &scenario).await {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                results.push((name, false, format!("{}", e)));
+                continue;
+            }
+        };
+        match errors.first() {
+            None => results.push((name, true, format!("{} batches", batch_amount))),
+            Some(error) => results.push((name, false, error.clone())),
+        }
+    }
+    let width = results
+        .iter()
+        .map(|(name, _, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    println!("{:<width$}  RESULT  DETAIL", "SCENARIO", width = width);
+    for (name, passed, detail) in results.iter() {
+        // 错误信息可能有多行，只打印第一行
+        let detail = detail.lines().next().unwrap_or("");
+        let result = if *passed { "PASS" } else { "FAIL" };
+        println!("{:<width$}  {:<6}  {}", name, result, detail, width = width);
+    }
+    let failed = results.iter().filter(|(_, passed, _)| !passed).count();
+    println!(
+        "{} scenarios, {} passed, {} failed",
+        results.len(),
+        results.len() - failed,
+        failed
+    );
+    if failed > 0 {
+        return Err(anyhow::Error::msg(format!(
+            "{} of {} scenarios failed",
+            failed,
+            results.len()
+        )));
+    }
+    Ok(())
+}
+
+/// 把general log中每个连接的sql写入一个会话文件
+async fn import<'a>(matches: &ArgMatches<'a>) -> Result<()> {
+    let content = file::read_file(matches.value_of(arg::GENERAL_LOG).unwrap()).await?;
+    let connections = import::parse_general_log(&content)?;
+    if connections.is_empty() {
+        return Err(anyhow::Error::msg("No sql is found in the general log"));
+    }
+    let dir = Path::new(matches.value_of(arg::OUTPUT_DIR).unwrap());
+    fs::create_dir_all(dir)?;
+    let mut session_files = vec![];
+    for connection in connections.iter() {
+        let session_file = dir.join(format!("conn-{}.sql", connection.id));
+        fs::write(&session_file, import::format_session_file(&connection.sqls))?;
+        println!(
+            "Connection {}: {} sqls -> {}",
+            connection.id,
+            connection.sqls.len(),
+            session_file.display()
+        );
+        session_files.push(session_file.display().to_string());
+    }
+    println!("Session files: {}", session_files.join(" "));
+    Ok(())
+}
 
+async fn run<'a>(matches: ArgMatches<'a>) -> Result<()> {
+    // 使用场景文件时，参数在子命令中
+    let (matches, scenario_file) = match matches.subcommand() {
+        (arg::RUN, Some(run_matches)) => (run_matches, run_matches.value_of(arg::SCENARIO)),
+        (arg::SUITE, Some(suite_matches)) => {
+            init_log(suite_matches.value_of(arg::LOG_CONFIG_FILE))?;
+            return run_suite(suite_matches).await;
+        }
+        (arg::IMPORT, Some(import_matches)) => {
+            init_log(import_matches.value_of(arg::LOG_CONFIG_FILE))?;
+            return import(import_matches).await;
+        }
+        _ => (&matches, None),
+    };
+    init_log(matches.value_of(arg::LOG_CONFIG_FILE))?;
+    let scenario = load_scenario(matches, scenario_file).await?;
+    if matches.is_present(arg::DRY_RUN) {
+        return dry_run(matches, &scenario).await;
+    }
+    execute_matrix(matches, &scenario).await?;
     Ok(())
 }
 
@@ -0,0 +1,161 @@
+use anyhow::{Error, Result};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+/// 批次的执行进度，批次按提交到线程池的顺序编号，从0开始
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Progress {
+    /// 编号小于该值的批次都已经完成
+    completed: u64,
+    /// 编号不小于`completed`，但已经完成的批次
+    completed_after: BTreeSet<u64>,
+    /// 已经提交还没完成的批次编号，及批次在字典序中的索引
+    in_flight: BTreeMap<u64, String>,
+}
+
+impl Progress {
+    pub fn is_completed(&self, seq: u64) -> bool {
+        seq < self.completed || self.completed_after.contains(&seq)
+    }
+
+    pub fn submit(&mut self, seq: u64, idx: &BigUint) {
+        self.in_flight.insert(seq, idx.to_string());
+    }
+
+    pub fn complete(&mut self, seq: u64) {
+        // 比较模式下，同一个批次会在每个数据库上完成一次
+        if self.is_completed(seq) {
+            return;
+        }
+        self.in_flight.remove(&seq);
+        self.completed_after.insert(seq);
+        // 推进连续完成的位置
+        while self.completed_after.remove(&self.completed) {
+            self.completed += 1;
+        }
+    }
+
+    /// 把指定的批次视为未完成后的进度，恢复时这些批次会重新执行
+    pub fn excluding(&self, seqs: &[u64]) -> Progress {
+        let mut progress = self.clone();
+        for &seq in seqs {
+            if seq < progress.completed {
+                progress.completed_after.extend(seq + 1..progress.completed);
+                progress.completed = seq;
+            } else {
+                progress.completed_after.remove(&seq);
+            }
+        }
+        progress
+    }
+
+    /// 连续完成的批次数量
+    pub fn completed(&self) -> u64 {
+        self.completed
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+/// 两条sql的位置，位置为(文件索引, sql索引)
+pub type SqlPair = ((usize, usize), (usize, usize));
+
+/// 生成交错排列的选项。检查点按提交顺序记录完成的批次，恢复时
+/// 选项必须相同，生成的批次及顺序才相同
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Generation {
+    /// 随机抽样的数量
+    pub sample: Option<usize>,
+    /// 随机抽样的种子
+    pub seed: Option<u64>,
+    pub allow_duplicates: bool,
+    /// `--range`或`--shard`指定的索引范围
+    pub range: Option<(String, String)>,
+    pub por: bool,
+    /// 声明可交换的sql
+    pub commute: Vec<SqlPair>,
+    /// 先后约束
+    pub happens_before: Vec<SqlPair>,
+    pub max_switches: Option<usize>,
+    pub pairwise: bool,
+    pub fewest_switches_first: bool,
+    /// 做对称归约的相同文件
+    pub symmetry: Vec<Vec<usize>>,
+}
+
+/// 检查点，保存执行进度及每个数据库累计的统计数据
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// 每个文件的块数量，恢复时用于检查是否同一个任务
+    pub sizes: Vec<usize>,
+    /// 生成交错排列的选项，恢复时用于检查是否同样的批次
+    #[serde(default)]
+    pub generation: Generation,
+    pub progress: Progress,
+    /// 每个数据库的统计数据
+    pub statistics: Vec<(String, serde_json::Value)>,
+}
+
+impl Checkpoint {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let content = fs::read(path)?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    /// 先写到临时文件再重命名，避免写到一半时中断导致检查点损坏
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// 检查检查点是否由同样的sql文件及生成选项生成，并丢弃未完成的
+    /// 批次，它们会被重新执行
+    pub fn resume(mut self, sizes: &[usize], generation: &Generation) -> Result<Self> {
+        if self.sizes != sizes {
+            return Err(Error::msg(format!(
+                "Checkpoint is generated from sql files with block sizes {:?}, but current block sizes are {:?}",
+                self.sizes, sizes
+            )));
+        }
+        if &self.generation != generation {
+            return Err(Error::msg(format!(
+                "Checkpoint is generated with options {:?}, but current options are {:?}",
+                self.generation, generation
+            )));
+        }
+        self.progress.in_flight.clear();
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_excluding() {
+        let mut progress = Progress::default();
+        for seq in [0, 1, 2, 3, 5, 6].iter() {
+            progress.complete(*seq);
+        }
+        assert_eq!(progress.completed(), 4);
+        let excluded = progress.excluding(&[1, 6]);
+        assert_eq!(excluded.completed(), 1);
+        let completed: Vec<bool> = (0..8).map(|seq| excluded.is_completed(seq)).collect();
+        assert_eq!(
+            completed,
+            vec![true, false, true, true, false, true, false, false]
+        );
+        // 原来的进度不变
+        assert_eq!(progress.completed(), 4);
+    }
+}
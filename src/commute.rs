@@ -0,0 +1,480 @@
+use anyhow::{Error, Result};
+use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::algo::Pruner;
+use crate::file::Sql;
+
+/// 结束FROM子句的关键字
+const FROM_CLAUSE_ENDS: &[&str] = &[
+    "where", "group", "order", "limit", "having", "union", "on", "using", "set", "values",
+    "for", "lock", "join", "inner", "left", "right", "cross", "natural", "straight_join", ")",
+    ";",
+];
+
+/// sql访问表的方式
+enum Access {
+    /// 无法判断访问的表，或者处于显式事务中，与所有sql都不可交换
+    Unknown,
+    Read(HashSet<String>),
+    Write(HashSet<String>),
+}
+
+impl Access {
+    fn commutes_with(&self, other: &Access) -> bool {
+        match (self, other) {
+            (Access::Unknown, _) | (_, Access::Unknown) => false,
+            (Access::Read(_), Access::Read(_)) => true,
+            (Access::Read(a), Access::Write(b))
+            | (Access::Write(a), Access::Read(b))
+            | (Access::Write(a), Access::Write(b)) => a.is_disjoint(b),
+        }
+    }
+}
+
+/// 把sql拆成小写的单词，逗号、括号及分号单独作为一个单词
+fn tokenize(sql: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    for c in sql.chars() {
+        match c {
+            '`' => {}
+            ',' | '(' | ')' | ';' => {
+                if !token.is_empty() {
+                    tokens.push(token.to_lowercase());
+                    token.clear();
+                }
+                tokens.push(c.to_string());
+            }
+            c if c.is_whitespace() => {
+                if !token.is_empty() {
+                    tokens.push(token.to_lowercase());
+                    token.clear();
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token.to_lowercase());
+    }
+    tokens
+}
+
+/// 找出sql访问的所有表，表名后面跟着的别名会被忽略
+fn tables(tokens: &[String]) -> HashSet<String> {
+    let mut tables = HashSet::new();
+    let mut in_from = false;
+    let mut expect_table = false;
+    let mut prev = "";
+    for token in tokens {
+        let token = token.as_str();
+        // `FOR UPDATE`及`ON DUPLICATE KEY UPDATE`后面不是表名
+        let after_update = prev == "for" || prev == "key";
+        prev = token;
+        if expect_table {
+            expect_table = false;
+            if token != "(" && token != "select" {
+                tables.insert(token.to_owned());
+                continue;
+            }
+        }
+        match token {
+            "from" => {
+                in_from = true;
+                expect_table = true;
+            }
+            "update" if after_update => in_from = false,
+            "join" | "into" | "update" | "table" => expect_table = true,
+            "," if in_from => expect_table = true,
+            _ if FROM_CLAUSE_ENDS.contains(&token) => in_from = false,
+            _ => {}
+        }
+    }
+    tables
+}
+
+/// 分析一条自动提交的sql访问表的方式
+fn analyze(sql: &str) -> Access {
+    let tokens = tokenize(sql);
+    let tables = tables(&tokens);
+    if tables.is_empty() {
+        return Access::Unknown;
+    }
+    let locking = tokens
+        .windows(2)
+        .any(|w| (w[0] == "for" && (w[1] == "update" || w[1] == "share")) || w[0] == "lock");
+    match tokens.first().map(String::as_str) {
+        Some("select") if locking => Access::Write(tables),
+        Some("select") => Access::Read(tables),
+        Some("insert") | Some("replace") | Some("update") | Some("delete") => {
+            Access::Write(tables)
+        }
+        _ => Access::Unknown,
+    }
+}
+
+/// 判断sql是否开启或结束了显式事务，返回执行后是否处于显式事务中
+fn transaction_state(sql: &str, in_transaction: bool) -> bool {
+    let tokens = tokenize(sql);
+    let words: Vec<&str> = tokens.iter().map(String::as_str).collect();
+    match words.as_slice() {
+        ["begin", ..] | ["start", "transaction", ..] => true,
+        ["commit", ..] | ["rollback", ..] => false,
+        ["set", rest @ ..] if matches!(rest.first(), Some(w) if w.contains("autocommit")) => {
+            // 关闭自动提交后，直到重新打开前都处于事务中
+            !rest.iter().any(|w| w.ends_with('1') || w.ends_with("on"))
+        }
+        _ => in_transaction,
+    }
+}
+
+/// 不同文件的sql两两之间是否可以交换执行顺序，同一个文件的sql
+/// 总是不可交换
+pub struct Independence {
+    /// 每个文件第一条sql在矩阵中的位置
+    offsets: Vec<usize>,
+    total: usize,
+    matrix: Vec<bool>,
+}
+
+impl Independence {
+    /// 根据sql访问的表推断，只读同一张表，或者访问不同表的两条sql
+    /// 可以交换。显式事务中的sql，会结束事务或无法判断访问的表的
+    /// sql，与其它sql都不可交换。触发器及外键等隐式的访问无法推断
+    pub fn infer(sqls_list: &[Vec<Sql>]) -> Self {
+        let mut offsets = vec![];
+        let mut accesses = vec![];
+        for sqls in sqls_list {
+            offsets.push(accesses.len());
+            let mut in_transaction = false;
+            for sql in sqls {
+                let was_in_transaction = in_transaction;
+                in_transaction = transaction_state(&sql.text, in_transaction);
+                if was_in_transaction || in_transaction {
+                    accesses.push(Access::Unknown);
+                } else {
+                    accesses.push(analyze(&sql.text));
+                }
+            }
+        }
+        let total = accesses.len();
+        let mut matrix = vec![false; total * total];
+        for (a, access_a) in accesses.iter().enumerate() {
+            for (b, access_b) in accesses.iter().enumerate() {
+                matrix[a * total + b] = access_a.commutes_with(access_b);
+            }
+        }
+        let mut independence = Independence {
+            offsets,
+            total,
+            matrix,
+        };
+        // 同一个文件的sql必须保持顺序
+        for (file_idx, sqls) in sqls_list.iter().enumerate() {
+            for a in 0..sqls.len() {
+                for b in 0..sqls.len() {
+                    independence.set((file_idx, a), (file_idx, b), false);
+                }
+            }
+        }
+        independence
+    }
+
+    fn set(&mut self, a: (usize, usize), b: (usize, usize), independent: bool) {
+        let a = self.offsets[a.0] + a.1;
+        let b = self.offsets[b.0] + b.1;
+        self.matrix[a * self.total + b] = independent;
+        self.matrix[b * self.total + a] = independent;
+    }
+
+    fn contains(&self, (file_idx, sql_idx): (usize, usize)) -> bool {
+        match self.offsets.get(file_idx) {
+            Some(offset) => {
+                let end = self.offsets.get(file_idx + 1).unwrap_or(&self.total);
+                offset + sql_idx < *end
+            }
+            None => false,
+        }
+    }
+
+    /// 声明不同文件的两条sql可以交换
+    pub fn declare(&mut self, a: (usize, usize), b: (usize, usize)) -> Result<()> {
+        for &(file_idx, sql_idx) in &[a, b] {
+            if !self.contains((file_idx, sql_idx)) {
+                return Err(Error::msg(format!(
+                    "There is no sql at file {} row {}",
+                    file_idx + 1,
+                    sql_idx + 1
+                )));
+            }
+        }
+        if a.0 == b.0 {
+            return Err(Error::msg(format!(
+                "SQLs in the same file(file {}) can not commute",
+                a.0 + 1
+            )));
+        }
+        self.set(a, b, true);
+        Ok(())
+    }
+
+    /// 声明两条sql不可交换，有先后约束的sql不能交换，否则等价类中
+    /// 保留的交错排列可能不满足约束
+    pub fn depend(&mut self, a: (usize, usize), b: (usize, usize)) {
+        if self.contains(a) && self.contains(b) {
+            self.set(a, b, false);
+        }
+    }
+
+    pub fn independent(&self, a: (usize, usize), b: (usize, usize)) -> bool {
+        let a = self.offsets[a.0] + a.1;
+        let b = self.offsets[b.0] + b.1;
+        self.matrix[a * self.total + b]
+    }
+
+    /// 块中的每条sql都可以交换时，两个块才可以交换
+    fn group(&self, blocks_list: &[Vec<Range<usize>>]) -> Independence {
+        let mut offsets = vec![];
+        let mut blocks = vec![];
+        for (file_idx, file_blocks) in blocks_list.iter().enumerate() {
+            offsets.push(blocks.len());
+            for block in file_blocks {
+                blocks.push((file_idx, block.clone()));
+            }
+        }
+        let total = blocks.len();
+        let mut matrix = vec![false; total * total];
+        for (a, (file_a, block_a)) in blocks.iter().enumerate() {
+            for (b, (file_b, block_b)) in blocks.iter().enumerate() {
+                matrix[a * total + b] = block_a.clone().all(|sql_a| {
+                    block_b
+                        .clone()
+                        .all(|sql_b| self.independent((*file_a, sql_a), (*file_b, sql_b)))
+                });
+            }
+        }
+        Independence {
+            offsets,
+            total,
+            matrix,
+        }
+    }
+
+    /// 偏序归约的剪枝条件，每个等价类（Mazurkiewicz trace）只保留
+    /// 字典序最小的交错排列
+    ///
+    /// 字典序最小的交错排列中不会出现`b u a`这样的片段：`a`所在文件
+    /// 比`b`的小，且`a`与`b`及`u`中的每个块都可以交换。追加`a`时，
+    /// 从后往前检查，遇到不可交换的块就停止
+    ///
+    /// # Arguments
+    ///
+    /// * `blocks_list` - 每个文件的块，交错排列以块为单位
+    pub fn into_pruner(self, blocks_list: &[Vec<Range<usize>>]) -> Pruner {
+        let independence = self.group(blocks_list);
+        Arc::new(move |result, curs, i| {
+            let next = (i, curs[i]);
+            for &prev in result.iter().rev() {
+                if !independence.independent(prev, next) {
+                    return true;
+                }
+                if prev.0 > i {
+                    return false;
+                }
+            }
+            true
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algo::InterlacePermutations;
+    use std::collections::HashMap;
+
+    fn sqls(texts: &[&str]) -> Vec<Sql> {
+        texts
+            .iter()
+            .map(|text| Sql {
+                text: String::from(*text),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn table_set(sql: &str) -> Vec<String> {
+        let mut tables: Vec<String> = tables(&tokenize(sql)).into_iter().collect();
+        tables.sort();
+        tables
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("SELECT `a`,b FROM t1(x);"),
+            vec!["select", "a", ",", "b", "from", "t1", "(", "x", ")", ";"]
+        );
+    }
+
+    #[test]
+    fn test_tables() {
+        assert_eq!(
+            table_set("select * from t1 a join t2 b on a.id = b.id"),
+            vec!["t1", "t2"]
+        );
+        assert_eq!(table_set("select * from t1, t2 where 1"), vec!["t1", "t2"]);
+        assert_eq!(table_set("insert into t3 values (1)"), vec!["t3"]);
+        assert_eq!(
+            table_set("insert into t values (1) on duplicate key update v = 1"),
+            vec!["t"]
+        );
+        assert_eq!(table_set("update t set v = 1"), vec!["t"]);
+        assert_eq!(table_set("select * from t for update"), vec!["t"]);
+        assert_eq!(
+            table_set("select * from (select 1) a"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_analyze() {
+        assert!(matches!(analyze("select * from t"), Access::Read(_)));
+        assert!(matches!(
+            analyze("select * from t for update"),
+            Access::Write(_)
+        ));
+        assert!(matches!(
+            analyze("select * from t lock in share mode"),
+            Access::Write(_)
+        ));
+        assert!(matches!(analyze("delete from t"), Access::Write(_)));
+        assert!(matches!(analyze("select 1"), Access::Unknown));
+        assert!(matches!(analyze("set @a = 1"), Access::Unknown));
+        assert!(analyze("select * from t").commutes_with(&analyze("select * from t")));
+        assert!(analyze("update a set v = 1").commutes_with(&analyze("select * from b")));
+        assert!(!analyze("update a set v = 1").commutes_with(&analyze("select * from a")));
+    }
+
+    #[test]
+    fn test_transaction_state() {
+        assert!(transaction_state("BEGIN", false));
+        assert!(transaction_state("start transaction read only", false));
+        assert!(!transaction_state("commit", true));
+        assert!(!transaction_state("rollback", true));
+        assert!(transaction_state("set autocommit = 0", false));
+        assert!(!transaction_state("set autocommit = 1", true));
+        assert!(transaction_state("select * from t", true));
+        assert!(!transaction_state("select * from t", false));
+    }
+
+    #[test]
+    fn test_infer() {
+        let sqls_list = vec![
+            sqls(&["begin", "update a set v = 1", "commit"]),
+            sqls(&["update a set v = 2", "select * from b"]),
+        ];
+        let independence = Independence::infer(&sqls_list);
+        // 显式事务中的sql与其它sql都不可交换
+        assert!(!independence.independent((0, 1), (1, 1)));
+        assert!(!independence.independent((0, 2), (1, 1)));
+        assert!(!independence.independent((1, 0), (1, 1)));
+    }
+
+    /// 暴力计算每个交错排列所在的等价类：交换相邻的可交换块得到的
+    /// 交错排列属于同一个等价类，返回每个等价类中字典序最小的交错排列
+    fn lex_min_of_traces(
+        sizes: &[usize],
+        independent: impl Fn((usize, usize), (usize, usize)) -> bool,
+    ) -> Vec<Vec<(usize, usize)>> {
+        let batches: Vec<_> = InterlacePermutations::new(sizes.to_vec()).collect();
+        let idxs: HashMap<_, _> = batches
+            .iter()
+            .enumerate()
+            .map(|(idx, batch)| (batch.clone(), idx))
+            .collect();
+        let mut classes: Vec<usize> = (0..batches.len()).collect();
+        fn find(classes: &mut Vec<usize>, idx: usize) -> usize {
+            if classes[idx] != idx {
+                let root = find(classes, classes[idx]);
+                classes[idx] = root;
+            }
+            classes[idx]
+        }
+        for (idx, batch) in batches.iter().enumerate() {
+            for pos in 0..batch.len().saturating_sub(1) {
+                let (a, b) = (batch[pos], batch[pos + 1]);
+                if a.0 == b.0 || !independent(a, b) {
+                    continue;
+                }
+                let mut swapped = batch.clone();
+                swapped.swap(pos, pos + 1);
+                let (x, y) = (find(&mut classes, idx), find(&mut classes, idxs[&swapped]));
+                classes[x.max(y)] = x.min(y);
+            }
+        }
+        // 按字典序生成，等价类中第一个出现的就是字典序最小的
+        let mut seen = HashSet::new();
+        let mut result = vec![];
+        for (idx, batch) in batches.iter().enumerate() {
+            if seen.insert(find(&mut classes, idx)) {
+                result.push(batch.clone());
+            }
+        }
+        result
+    }
+
+    fn pruned(sizes: &[usize], pruner: Pruner) -> Vec<Vec<(usize, usize)>> {
+        InterlacePermutations::new(sizes.to_vec())
+            .with_pruner(pruner)
+            .collect()
+    }
+
+    #[test]
+    fn test_pruner_keeps_one_per_trace() {
+        let sqls_list = vec![
+            sqls(&["select * from a", "update b set v = 1"]),
+            sqls(&["select * from a", "update c set v = 1", "select * from b"]),
+            sqls(&["update b set v = 2", "update d set v = 2"]),
+        ];
+        let blocks_list: Vec<Vec<Range<usize>>> = sqls_list
+            .iter()
+            .map(|sqls| (0..sqls.len()).map(|idx| idx..idx + 1).collect())
+            .collect();
+        let sizes = [2, 3, 2];
+        let independence = Independence::infer(&sqls_list);
+        let expected = lex_min_of_traces(&sizes, |a, b| independence.independent(a, b));
+        assert!(expected.len() < InterlacePermutations::new(sizes.to_vec()).count());
+        assert_eq!(
+            pruned(&sizes, independence.into_pruner(&blocks_list)),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_pruner_with_blocks_and_declared() {
+        let sqls_list = vec![
+            sqls(&[
+                "update a set v = 1",
+                "update b set v = 1",
+                "select * from c",
+            ]),
+            sqls(&["update a set v = 2", "update c set v = 2"]),
+        ];
+        let blocks_list = vec![vec![0..2, 2..3], vec![0..1, 1..2]];
+        let sizes = [2, 2];
+        let mut independence = Independence::infer(&sqls_list);
+        independence.declare((0, 0), (1, 0)).unwrap();
+        assert!(independence.declare((0, 0), (0, 1)).is_err());
+        assert!(independence.declare((0, 3), (1, 0)).is_err());
+        let blocks = independence.group(&blocks_list);
+        let expected = lex_min_of_traces(&sizes, |a, b| blocks.independent(a, b));
+        assert!(expected.len() < InterlacePermutations::new(sizes.to_vec()).count());
+        assert_eq!(
+            pruned(&sizes, independence.into_pruner(&blocks_list)),
+            expected
+        );
+    }
+}
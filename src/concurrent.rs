@@ -1,28 +1,123 @@
 use anyhow::{Error, Result};
 use mysql_async::prelude::*;
-use mysql_async::{Opts, Pool};
-use std::collections::VecDeque;
+use mysql_async::{Conn, Opts, Pool};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::{Mutex, MutexGuard, Notify, OwnedSemaphorePermit, Semaphore};
 use tokio::task::JoinHandle;
 
+use crate::algo::PairCoverage;
 use crate::arg;
+use crate::checkpoint::{Checkpoint, Generation, Progress};
+use crate::file::Sql;
+use crate::session::{self, Mismatch, Session, SqlResult};
+
+/// 保存检查点的间隔
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+/// 共享前缀执行时，执行完前缀第k条sql后保存的savepoint名为该前缀加k
+const SAVEPOINT_PREFIX: &str = "interlace_prefix_";
+/// 共享前缀执行时，只有最后这么多条sql不同的批次属于同一棵子树，
+/// 由同一个Worker执行
+const SUBTREE_HEIGHT: usize = 4;
+/// 一棵子树中最多的批次数量，即`SUBTREE_HEIGHT`条sql的排列数
+const SUBTREE_BATCHES: usize = 24;
+/// 输出捕获的sql执行结果的日志target
+const CAPTURE_TARGET: &str = "sql_permutation::capture";
+
+/// 一个批次，即一种交错排列
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Batch {
+    /// 交错排列在字典序中的索引，从0开始，在不同机器上是一致的
+    pub idx: BigUint,
+    /// 每个sql的(文件索引, 文件中的位置)，块已经展开为其中的每个sql
+    pub sql_idxs: Vec<(usize, usize)>,
+    /// 提交到线程池的顺序编号，由线程池分配
+    #[serde(skip)]
+    seq: u64,
+    /// 开启捕获时，每个sql的执行结果，与`sql_idxs`一一对应，没有执行
+    /// 完的sql为None
+    #[serde(skip)]
+    pub results: Vec<Option<SqlResult>>,
+    /// 比较模式下，执行该批次的Worker的编号，为None时任何Worker都可以执行
+    #[serde(skip)]
+    target: Option<usize>,
+}
+
+impl Batch {
+    pub fn new(idx: BigUint, sql_idxs: Vec<(usize, usize)>) -> Self {
+        Batch {
+            idx,
+            sql_idxs,
+            seq: 0,
+            results: vec![],
+            target: None,
+        }
+    }
+}
 
 pub struct StateMut {
     /// 是否运行中
     running: bool,
     /// 是否由于异常被中止
     abort: bool,
-    queue: VecDeque<(OwnedSemaphorePermit, Vec<(usize, usize)>)>,
+    queue: VecDeque<(OwnedSemaphorePermit, Batch)>,
     waiting_worker_signals: VecDeque<Arc<Notify>>,
     total_statistics: Vec<(String, Statistics)>,
+    /// 下一个提交的批次的编号
+    next_seq: u64,
+    progress: Progress,
+    /// 保存检查点的文件，为None时不保存
+    checkpoint_file: Option<PathBuf>,
+    /// 每个文件的块数量，保存到检查点
+    sizes: Vec<usize>,
+    /// 生成交错排列的选项，保存到检查点
+    generation: Generation,
+    last_checkpoint: Instant,
+    /// 每个数据库最近一次快照的统计数据，用于保存检查点
+    statistics_snapshots: Vec<(String, serde_json::Value)>,
+    /// 每个数据库在最近一次快照后完成的批次，统计数据还不包含它们，
+    /// 保存检查点时视为未完成
+    unsnapshotted: HashMap<String, Vec<u64>>,
+    /// 上次保存检查点后，还需要在完成下一个批次时快照统计数据的数据库
+    snapshot_due: HashSet<String>,
+    /// 从检查点恢复的统计数据，由Worker开始执行时取出
+    resumed_statistics: Vec<(String, Statistics)>,
+    /// 是否每个批次都提交给每个Worker执行
+    broadcast: bool,
+    /// 是否比较模式，比较每个批次在每个数据库上的结果
+    compare: bool,
+    /// Worker的数量，比较模式下每个批次的结果数量
+    worker_amount: usize,
+    /// 是否按子树把批次分配给Worker，用于共享前缀
+    share_prefix: bool,
+    /// 上一个批次所在子树的根（除最后`SUBTREE_HEIGHT`条外的sql），
+    /// 及分配到的Worker
+    subtree: Option<(Vec<(usize, usize)>, usize)>,
+    /// 比较模式下，还没收到所有数据库结果的批次，按批次编号存放
+    /// 已经收到的每个数据库的结果
+    comparisons: HashMap<u64, Vec<(String, Outcome)>>,
+    /// 比较模式下，在不同数据库上结果不同的批次
+    divergences: Vec<Divergence>,
+    /// 两两顺序覆盖模式下，完成的批次覆盖的先后顺序
+    coverage: Option<Coverage>,
 }
 
 impl StateMut {
     fn clear(&mut self) {
         // 清空key，返回produce_permit到信号量
         while let Some((_permit, _)) = self.queue.pop_front() {}
+        self.notify_all();
+    }
+
+    fn notify_all(&mut self) {
         // 通知所有等待的Worker
         while let Some(signal) = self.waiting_worker_signals.pop_front() {
             signal.notify();
@@ -35,10 +130,171 @@ impl StateMut {
         self.clear();
     }
 
+    /// 不再接收新的批次，Worker执行完队列里的批次后结束
     pub async fn shutdown(&mut self) {
+        self.running = false;
+        self.notify_all();
+    }
+
+    /// 不再接收新的批次，并丢弃队列里的批次，Worker执行完当前的批次
+    /// 后结束。被丢弃的批次在检查点中是未完成的
+    pub async fn interrupt(&mut self) {
         self.running = false;
         self.clear();
     }
+
+    /// 记录批次已经完成，开启检查点时定期保存检查点
+    ///
+    /// 统计数据会越来越大，每次完成批次都序列化的代价太高，只在保存
+    /// 检查点时快照完成该批次的数据库，其它数据库在各自完成下一个
+    /// 批次时快照
+    fn complete(&mut self, seq: u64, mysql_target: &str, statistics: &Statistics) {
+        self.progress.complete(seq);
+        if self.checkpoint_file.is_none() {
+            return;
+        }
+        self.unsnapshotted
+            .entry(mysql_target.to_owned())
+            .or_default()
+            .push(seq);
+        let due = self.last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL;
+        if !due && !self.snapshot_due.remove(mysql_target) {
+            return;
+        }
+        self.snapshot(mysql_target, statistics);
+        if due {
+            self.snapshot_due = self.unsnapshotted.keys().cloned().collect();
+            if let Err(e) = self.save_checkpoint() {
+                log::warn!("saving checkpoint failed:\n{}", e);
+            }
+        }
+    }
+
+    /// 序列化数据库当前的统计数据，用于保存检查点
+    fn snapshot(&mut self, mysql_target: &str, statistics: &Statistics) {
+        let snapshot = match serde_json::to_value(statistics) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                log::warn!("serializing statistics failed:\n{}", e);
+                return;
+            }
+        };
+        self.unsnapshotted.remove(mysql_target);
+        match self
+            .statistics_snapshots
+            .iter_mut()
+            .find(|(target, _)| target == mysql_target)
+        {
+            Some((_, s)) => *s = snapshot,
+            None => self
+                .statistics_snapshots
+                .push((mysql_target.to_owned(), snapshot)),
+        }
+    }
+
+    fn save_checkpoint(&mut self) -> Result<()> {
+        let checkpoint_file = match &self.checkpoint_file {
+            Some(checkpoint_file) => checkpoint_file,
+            None => return Ok(()),
+        };
+        // 统计数据还不包含的批次，恢复时重新执行
+        let unsnapshotted: Vec<u64> = self.unsnapshotted.values().flatten().copied().collect();
+        let checkpoint = Checkpoint {
+            sizes: self.sizes.clone(),
+            generation: self.generation.clone(),
+            progress: self.progress.excluding(&unsnapshotted),
+            statistics: self.statistics_snapshots.clone(),
+        };
+        checkpoint.save(checkpoint_file)?;
+        self.last_checkpoint = Instant::now();
+        log::debug!(
+            "checkpoint saved, completed batches: {}",
+            self.progress.completed()
+        );
+        Ok(())
+    }
+
+    /// 比较模式下，记录批次在一个数据库上的结果，收到所有数据库的
+    /// 结果后，与第一个数据库的结果比较
+    fn compare(&mut self, batch: &Batch, mysql_target: &str, outcome: Outcome) {
+        let outcomes = self.comparisons.entry(batch.seq).or_default();
+        outcomes.push((mysql_target.to_owned(), outcome));
+        if outcomes.len() < self.worker_amount {
+            return;
+        }
+        let mut outcomes = self.comparisons.remove(&batch.seq).unwrap();
+        // 按数据库的顺序比较，输出稳定
+        outcomes.sort_by(|a, b| a.0.cmp(&b.0));
+        if outcomes
+            .iter()
+            .all(|(_, outcome)| *outcome == outcomes[0].1)
+        {
+            return;
+        }
+        log::debug!("batch #{} diverges between databases", batch.idx);
+        let mut batch = batch.clone();
+        batch.results.clear();
+        self.divergences.push(Divergence { batch, outcomes });
+    }
+
+    /// 取出数据库从检查点恢复的统计数据，没有时新建
+    fn take_resumed_statistics(&mut self, mysql_target: &str) -> Statistics {
+        match self
+            .resumed_statistics
+            .iter()
+            .position(|(target, _)| target == mysql_target)
+        {
+            Some(i) => self.resumed_statistics.remove(i).1,
+            None => Statistics::new(),
+        }
+    }
+
+    /// 共享前缀时，分配执行批次的Worker。同一棵子树的批次在字典序中
+    /// 是连续的，分配给同一个Worker；新的子树分配给排队的批次最少的
+    /// Worker
+    fn assign_subtree(&mut self, batch: &Batch) -> usize {
+        let depth = batch.sql_idxs.len().saturating_sub(SUBTREE_HEIGHT);
+        let root = &batch.sql_idxs[..depth];
+        if let Some((last_root, target)) = &self.subtree {
+            if last_root.as_slice() == root {
+                return *target;
+            }
+        }
+        let queue = &self.queue;
+        let target = (0..self.worker_amount)
+            .min_by_key(|&target| {
+                queue
+                    .iter()
+                    .filter(|(_, batch)| batch.target == Some(target))
+                    .count()
+            })
+            .unwrap_or(0);
+        self.subtree = Some((root.to_vec(), target));
+        target
+    }
+}
+
+/// 完成的批次中，不同文件的块两两之间出现过的先后顺序
+struct Coverage {
+    coverage: PairCoverage,
+    /// 每个块第一条sql在文件中的行，用于打印
+    rows: Vec<Vec<usize>>,
+    /// 每条sql所在的块，按(文件索引, 文件中的位置)存放
+    block_idxs: Vec<Vec<usize>>,
+}
+
+impl Coverage {
+    /// 记录批次中块之间的先后顺序，同一个块的sql在批次中是连续的
+    fn record(&mut self, batch: &Batch) {
+        let mut steps: Vec<(usize, usize)> = vec![];
+        for &(file_idx, sql_idx) in batch.sql_idxs.iter() {
+            let step = (file_idx, self.block_idxs[file_idx][sql_idx]);
+            if steps.last() != Some(&step) {
+                steps.push(step);
+            }
+        }
+        self.coverage.record(&steps);
+    }
 }
 
 pub struct State {
@@ -55,6 +311,24 @@ impl State {
                 queue: VecDeque::with_capacity(queue_capacity),
                 waiting_worker_signals: VecDeque::new(),
                 total_statistics: vec![],
+                next_seq: 0,
+                progress: Progress::default(),
+                checkpoint_file: None,
+                sizes: vec![],
+                generation: Generation::default(),
+                last_checkpoint: Instant::now(),
+                statistics_snapshots: vec![],
+                unsnapshotted: HashMap::new(),
+                snapshot_due: HashSet::new(),
+                resumed_statistics: vec![],
+                broadcast: false,
+                compare: false,
+                worker_amount: 0,
+                share_prefix: false,
+                subtree: None,
+                comparisons: HashMap::new(),
+                divergences: vec![],
+                coverage: None,
             }),
             produce_permits: Arc::new(Semaphore::new(queue_capacity)),
         }
@@ -65,20 +339,150 @@ impl State {
     }
 }
 
+/// 发生死锁的批次，及其被阻塞的sql，按索引从1开始
+type DeadlockBatch = (Batch, Vec<usize>);
+
+/// 检查sql返回的行，每条检查sql对应一个结果集
+pub type StateRows = Vec<Vec<Vec<String>>>;
+
+/// 批次执行后，检查sql得到的一种最终状态
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FinalState {
+    /// 检查sql返回的行的哈希值
+    pub hash: u64,
+    pub rows: StateRows,
+    /// 得到该状态的批次数量
+    pub amount: usize,
+    /// 得到该状态的一个批次
+    pub sample: Batch,
+}
+
+/// 一个批次的结果，包括最终状态及每个sql捕获的结果，用于与串行
+/// 执行的结果比较
+#[derive(Clone, PartialEq)]
+pub struct Outcome {
+    /// 最终状态的哈希值，没有检查sql时为None
+    pub state: Option<u64>,
+    /// 按(文件索引, 文件中的位置)存放的捕获的结果，没有捕获时为None
+    pub reads: Vec<Vec<Option<SqlResult>>>,
+}
+
+/// 每个数据库串行执行所有串行顺序得到的不同结果
+pub type SerialOutcomes = Vec<(String, Vec<Outcome>)>;
+
+/// 比较模式下，在不同数据库上结果不同的批次
+pub struct Divergence {
+    pub batch: Batch,
+    /// 每个数据库的结果
+    pub outcomes: Vec<(String, Outcome)>,
+}
+
+impl Divergence {
+    /// 描述一个sql的结果
+    fn describe(result: &Option<SqlResult>) -> String {
+        match result {
+            None => String::from("not executed"),
+            Some(SqlResult {
+                error: Some(code), ..
+            }) => format!("error {}", code),
+            Some(result) => {
+                let rows: Vec<String> = result
+                    .rows
+                    .iter()
+                    .map(|row| format!("({})", row.join(", ")))
+                    .collect();
+                format!(
+                    "rows: [{}], affected rows: {}",
+                    rows.join(", "),
+                    result.affected_rows
+                )
+            }
+        }
+    }
+
+    /// 打印批次，及结果不同的最终状态和sql
+    fn print(&self, sqls_list: &[Vec<Sql>]) {
+        log::info!("Batch #{} diverges between databases:", self.batch.idx);
+        Statistics::print_sql_batch(sqls_list, &self.batch, None, &[]);
+        let (_, first) = &self.outcomes[0];
+        if self.outcomes.iter().any(|(_, o)| o.state != first.state) {
+            let states: Vec<String> = self
+                .outcomes
+                .iter()
+                .map(|(mysql_target, o)| match o.state {
+                    Some(state) => format!("{} => {:016x}", mysql_target, state),
+                    None => format!("{} => none", mysql_target),
+                })
+                .collect();
+            log::info!("Final state: {}", states.join(", "));
+        }
+        for (file_idx, sql_idx) in self.batch.sql_idxs.iter() {
+            let first_result = &first.reads[*file_idx][*sql_idx];
+            if self
+                .outcomes
+                .iter()
+                .all(|(_, o)| o.reads[*file_idx][*sql_idx] == *first_result)
+            {
+                continue;
+            }
+            let sql = &sqls_list[*file_idx][*sql_idx];
+            log::info!("{} at {}:", sql.text, sql.position(*file_idx));
+            for (mysql_target, o) in self.outcomes.iter() {
+                log::info!(
+                    "    {} => {}",
+                    mysql_target,
+                    Self::describe(&o.reads[*file_idx][*sql_idx])
+                );
+            }
+        }
+    }
+}
+
+impl FinalState {
+    pub fn hash_rows(rows: &StateRows) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        rows.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Statistics {
     /// 正常完成时会被清空
-    cur_batch: Option<Vec<(usize, usize)>>,
+    cur_batch: Option<Batch>,
     /// 按索引从1开始，0代表无效，方便打印时不用每次解Option
     cur_batch_idx: usize,
-    last_batch: Option<Vec<(usize, usize)>>,
+    last_batch: Option<Batch>,
+    #[serde(skip)]
     error: Option<Error>,
     sql_amount: usize,
     batch_amount: usize,
     time: Duration,
     slowest_sql: Option<(usize, usize)>,
     slowest_sql_time: Duration,
-    slowest_batch: Option<Vec<(usize, usize)>>,
+    slowest_batch: Option<Batch>,
     slowest_batch_time: Duration,
+    /// 当前批次被阻塞的sql，按索引从1开始
+    cur_blocked: Vec<usize>,
+    blocked_amount: usize,
+    deadlock_amount: usize,
+    /// 发生死锁的批次及其被阻塞的sql
+    deadlock_batches: Vec<DeadlockBatch>,
+    /// 共享前缀执行时，由于与上一个批次的前缀相同而不用执行的sql数量
+    #[serde(default)]
+    shared_sql_amount: usize,
+    /// 开启检查时，批次执行后得到的各种最终状态
+    #[serde(default)]
+    final_states: Vec<FinalState>,
+    /// 当前批次的最终状态的哈希值
+    #[serde(skip)]
+    cur_final_state: Option<u64>,
+    /// 串行执行时，得到的各种不同的结果
+    #[serde(skip)]
+    outcomes: Vec<Outcome>,
+    /// 结果与任何一种串行执行都不同的批次
+    #[serde(default)]
+    non_serializable_batches: Vec<Batch>,
 }
 
 impl Statistics {
@@ -95,17 +499,63 @@ impl Statistics {
             slowest_sql_time: Duration::from_nanos(0),
             slowest_batch: None,
             slowest_batch_time: Duration::from_nanos(0),
+            cur_blocked: vec![],
+            blocked_amount: 0,
+            deadlock_amount: 0,
+            deadlock_batches: vec![],
+            shared_sql_amount: 0,
+            final_states: vec![],
+            cur_final_state: None,
+            outcomes: vec![],
+            non_serializable_batches: vec![],
+        }
+    }
+
+    fn record_sql(&mut self, file_idx: usize, sql_idx: usize, sql_time: Duration) {
+        if sql_time > self.slowest_sql_time {
+            self.slowest_sql_time = sql_time;
+            self.slowest_sql.replace((file_idx, sql_idx));
+        }
+    }
+
+    /// 把捕获的结果记录到当前批次的第`pos`个sql，从0开始
+    fn record_result(&mut self, pos: usize, result: Option<SqlResult>) {
+        if let (Some(batch), Some(result)) = (&mut self.cur_batch, result) {
+            if batch.results.len() < batch.sql_idxs.len() {
+                batch.results.resize(batch.sql_idxs.len(), None);
+            }
+            batch.results[pos] = Some(result);
+        }
+    }
+
+    /// 记录当前批次检查得到的最终状态
+    fn record_final_state(&mut self, rows: StateRows) {
+        let hash = FinalState::hash_rows(&rows);
+        self.cur_final_state = Some(hash);
+        match self
+            .final_states
+            .iter_mut()
+            .find(|state| state.hash == hash)
+        {
+            Some(state) => state.amount += 1,
+            None => self.final_states.push(FinalState {
+                hash,
+                rows,
+                amount: 1,
+                sample: self.cur_batch.clone().unwrap(),
+            }),
         }
     }
 
     /// # Arguments
     ///
     /// * `error_idx` - 从1开始，0代表无效
+    /// * `blocked` - 被阻塞的sql，从1开始
     fn print_sql_batch(
-        &self,
-        sqls_list: &Vec<Vec<String>>,
-        batch: &Vec<(usize, usize)>,
+        sqls_list: &[Vec<Sql>],
+        batch: &Batch,
         error_idx: Option<usize>,
+        blocked: &[usize],
     ) {
         let mut padding = "";
         // 0代表不存在
@@ -114,22 +564,44 @@ impl Statistics {
             padding = "       ";
             i = error_idx;
         }
-        for (idx, (file_idx, sql_idx)) in batch.iter().enumerate() {
+        if !blocked.is_empty() {
+            padding = "       ";
+        }
+        for (idx, (file_idx, sql_idx)) in batch.sql_idxs.iter().enumerate() {
             let mut padding = padding;
             if i == idx + 1 {
                 padding = "err -> ";
+            } else if blocked.contains(&(idx + 1)) {
+                padding = "blk -> ";
             }
+            let sql = &sqls_list[*file_idx][*sql_idx];
+            log::info!("{}{} at {}", padding, sql.text, sql.position(*file_idx));
+        }
+    }
+
+    /// 打印批次中捕获的每个sql返回的行及影响的行数
+    fn print_results(sqls_list: &[Vec<Sql>], batch: &Batch) {
+        for ((file_idx, sql_idx), result) in batch.sql_idxs.iter().zip(batch.results.iter()) {
+            let sql = &sqls_list[*file_idx][*sql_idx];
+            let result = match result {
+                Some(result) => result,
+                None => continue,
+            };
+            let rows: Vec<String> = result
+                .rows
+                .iter()
+                .map(|row| format!("({})", row.join(", ")))
+                .collect();
             log::info!(
-                "{}{} at (file {}, row {})",
-                padding,
-                sqls_list[*file_idx][*sql_idx],
-                file_idx + 1,
-                sql_idx + 1
+                "{} => rows: [{}], affected rows: {}",
+                sql.position(*file_idx),
+                rows.join(", "),
+                result.affected_rows
             );
         }
     }
 
-    pub fn print(&self, sqls_list: &Vec<Vec<String>>, abort: bool) {
+    pub fn print(&self, sqls_list: &Vec<Vec<Sql>>, abort: bool) {
         if let Some(error) = &self.error {
             log::info!("Error happend!\n{}", error);
             match &self.cur_batch {
@@ -141,8 +613,13 @@ impl Statistics {
                     );
                 }
                 Some(cur_batch) => {
-                    log::info!("Error happened while handling batch:");
-                    self.print_sql_batch(sqls_list, cur_batch, Some(self.cur_batch_idx));
+                    log::info!("Error happened while handling batch #{}:", cur_batch.idx);
+                    Self::print_sql_batch(
+                        sqls_list,
+                        cur_batch,
+                        Some(self.cur_batch_idx),
+                        &self.cur_blocked,
+                    );
                 }
             };
             return;
@@ -153,8 +630,8 @@ impl Statistics {
                     log::info!("No batch has been handled in this database.");
                 }
                 Some(last_batch) => {
-                    log::info!("Last handled batch:");
-                    self.print_sql_batch(sqls_list, last_batch, None);
+                    log::info!("Last handled batch #{}:", last_batch.idx);
+                    Self::print_sql_batch(sqls_list, last_batch, None, &[]);
                 }
             };
         } else {
@@ -168,26 +645,58 @@ impl Statistics {
                 self.batch_amount,
                 self.sql_amount
             );
+            if self.shared_sql_amount > 0 {
+                log::info!(
+                    "Total SQL skipped by prefix sharing: {}",
+                    self.shared_sql_amount
+                );
+            }
             log::info!(
                 "Average time(per batch): {:?}, Average time(per SQL): {:?}",
                 self.time / self.batch_amount as u32,
                 self.time / self.sql_amount as u32,
             );
             if let Some((file_idx, sql_idx)) = &self.slowest_sql {
+                let sql = &sqls_list[*file_idx][*sql_idx];
                 log::info!(
-                    "Slowest SQL time: {:?} - {} at (file {}, row {})",
+                    "Slowest SQL time: {:?} - {} at {}",
                     self.slowest_sql_time,
-                    sqls_list[*file_idx][*sql_idx],
-                    file_idx + 1,
-                    sql_idx + 1
+                    sql.text,
+                    sql.position(*file_idx)
                 );
             }
             if let Some(slowest_batch) = &self.slowest_batch {
                 log::info!(
-                    "Slowest batch time: {:?}\nSlowest batch:",
+                    "Slowest batch time: {:?}\nSlowest batch #{}:",
                     self.slowest_batch_time,
+                    slowest_batch.idx
                 );
-                self.print_sql_batch(sqls_list, slowest_batch, None);
+                Self::print_sql_batch(sqls_list, slowest_batch, None, &[]);
+            }
+            if self.blocked_amount > 0 {
+                log::info!(
+                    "Total SQL blocked: {}, Total batch deadlocked: {}",
+                    self.blocked_amount,
+                    self.deadlock_amount
+                );
+            }
+            for (deadlock_batch, blocked) in self.deadlock_batches.iter() {
+                log::info!(
+                    "Deadlock or livelock happened while handling batch #{}:",
+                    deadlock_batch.idx
+                );
+                Self::print_sql_batch(sqls_list, deadlock_batch, None, blocked);
+            }
+            if !self.non_serializable_batches.is_empty() {
+                log::info!(
+                    "Total batch not equivalent to any serial order: {}",
+                    self.non_serializable_batches.len()
+                );
+            }
+            for batch in self.non_serializable_batches.iter() {
+                log::info!("Non-serializable batch #{}:", batch.idx);
+                Self::print_sql_batch(sqls_list, batch, None, &[]);
+                Self::print_results(sqls_list, batch);
             }
         }
     }
@@ -196,17 +705,51 @@ impl Statistics {
 pub struct Worker {
     init_sqls: String,
     reset_sqls: String,
-    sqls_list: Vec<Vec<String>>,
+    sqls_list: Vec<Vec<Sql>>,
     mysql_pool: Pool,
     mysql_target: String,
     signal: Arc<Notify>,
+    /// 每个sql文件是否使用独立的会话
+    session_per_file: bool,
+    /// 会话的sql执行超过该时间，就认为会话被阻塞
+    block_threshold: Duration,
+    /// 是否共享相邻批次的相同前缀
+    share_prefix: bool,
+    /// 每个文件的块，共享前缀时只在块的边界保存savepoint
+    blocks_list: Vec<Vec<Range<usize>>>,
+    /// 每个文件的会话在批次开始前设置会话变量的sql
+    session_variables: Vec<Option<String>>,
+    /// 是否捕获每个sql的执行结果
+    capture: bool,
+    /// 每个批次后检查最终状态的sql
+    check_sqls: Vec<Sql>,
+    /// 是否记录每个批次的结果，用于串行执行
+    record_outcomes: bool,
+    /// 所有串行执行的结果，批次的结果与它们都不同时被认为不可串行化
+    serial_outcomes: Option<Arc<Vec<Outcome>>>,
+    /// 是否比较模式，数据库返回的错误作为sql的结果记录
+    compare: bool,
+    /// Worker在线程池中的编号，由线程池分配
+    id: usize,
+}
+
+/// 共享前缀执行时，保持事务的连接及已经执行的sql
+struct Prefix {
+    conn: Conn,
+    /// 已经执行的sql
+    sql_idxs: Vec<(usize, usize)>,
+    /// 保存了savepoint的位置，即保存时已经执行的sql数量，第0个
+    /// savepoint在事务开始时保存
+    savepoints: Vec<usize>,
+    /// 开启捕获时，已经执行的sql的结果
+    results: Vec<Option<SqlResult>>,
 }
 
 impl Worker {
     pub fn new(
         init_sqls: &String,
         reset_sqls: &String,
-        sqls_list: &Vec<Vec<String>>,
+        sqls_list: &Vec<Vec<Sql>>,
         mysql_opts: Opts,
     ) -> Self {
         // 由于线程及sql的量不大，且为常熟，直接拷贝
@@ -222,16 +765,228 @@ impl Worker {
             ),
             mysql_pool: Pool::new(mysql_opts),
             signal: Arc::new(Notify::new()),
+            session_per_file: false,
+            block_threshold: Duration::from_millis(500),
+            share_prefix: false,
+            blocks_list: vec![],
+            session_variables: vec![],
+            capture: false,
+            check_sqls: vec![],
+            record_outcomes: false,
+            serial_outcomes: None,
+            compare: false,
+            id: 0,
+        }
+    }
+
+    /// 设置每个sql文件是否使用独立的会话，开启后，不同文件的sql
+    /// 会在不同的连接上执行，从而测试会话之间的隔离性
+    pub fn session_per_file(mut self, session_per_file: bool) -> Self {
+        self.session_per_file = session_per_file;
+        self
+    }
+
+    /// 设置判断会话被阻塞的等待时间，仅在每个文件使用独立会话时有效
+    pub fn block_threshold(mut self, block_threshold: Duration) -> Self {
+        self.block_threshold = block_threshold;
+        self
+    }
+
+    /// 设置是否共享相邻批次的相同前缀。开启后，重置及初始化后在一个
+    /// 事务中执行所有批次，在后续批次可能分叉的位置保存savepoint，下一个
+    /// 批次回滚到与当前批次相同的前缀后再继续执行，不需要重新执行前缀
+    pub fn share_prefix(mut self, share_prefix: bool) -> Self {
+        self.share_prefix = share_prefix;
+        self
+    }
+
+    /// 设置每个文件的块，块中间的sql之后批次不会分叉，共享前缀时不需要
+    /// 保存savepoint
+    pub fn blocks_list(mut self, blocks_list: Vec<Vec<Range<usize>>>) -> Self {
+        self.blocks_list = blocks_list;
+        self
+    }
+
+    /// 设置每个文件的会话在批次开始前执行的`SET SESSION`语句，
+    /// 仅在每个文件使用独立会话时有效
+    pub fn session_variables(mut self, session_variables: Vec<Option<String>>) -> Self {
+        self.session_variables = session_variables;
+        self
+    }
+
+    /// 设置是否捕获每个sql返回的行、影响的行数、最后插入的id及警告数，
+    /// 捕获的结果记录到批次中，批次完成后输出到`sql_permutation::capture`
+    /// 日志
+    pub fn capture(mut self, capture: bool) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// 设置每个批次执行后、下一次重置前执行的检查sql，它们返回的行
+    /// 作为批次的最终状态
+    pub fn check_sqls(mut self, check_sqls: Vec<Sql>) -> Self {
+        self.check_sqls = check_sqls;
+        self
+    }
+
+    /// 执行检查sql，记录当前批次的最终状态
+    async fn check_final_state(&self, conn: &mut Conn, statistics: &mut Statistics) -> Result<()> {
+        if self.check_sqls.is_empty() {
+            return Ok(());
+        }
+        let mut rows = Vec::with_capacity(self.check_sqls.len());
+        for sql in self.check_sqls.iter() {
+            let result = session::execute(conn, sql, true).await.map_err(|e| {
+                Error::msg(format!("Executing check sql `{}` failed:\n{}", sql.text, e))
+            })?;
+            rows.push(result.map(|result| result.rows).unwrap_or_default());
+        }
+        statistics.record_final_state(rows);
+        Ok(())
+    }
+
+    /// 设置是否记录每个批次的结果，用于执行所有的串行顺序
+    pub fn record_outcomes(mut self, record_outcomes: bool) -> Self {
+        self.record_outcomes = record_outcomes;
+        self
+    }
+
+    /// 设置所有串行执行的结果，结果与它们都不同的批次会被记录为
+    /// 不可串行化
+    pub fn serial_outcomes(mut self, serial_outcomes: Arc<Vec<Outcome>>) -> Self {
+        self.serial_outcomes = Some(serial_outcomes);
+        self
+    }
+
+    /// 设置是否比较模式。开启后，每个批次在每个数据库上都执行一次，
+    /// 捕获每个sql的结果，数据库返回的错误不会中止执行，而是作为sql
+    /// 的结果，与其它数据库的结果比较
+    pub fn compare(mut self, compare: bool) -> Self {
+        self.compare = compare;
+        self
+    }
+
+    /// 开启捕获或者比较模式时，需要捕获每个sql的结果
+    fn capturing(&self) -> bool {
+        self.capture || self.compare
+    }
+
+    /// 比较模式下，数据库返回的错误，及与期望不一致的结果作为sql的
+    /// 结果记录，不中止执行
+    fn tolerate(&self, result: Result<Option<SqlResult>>) -> Result<Option<SqlResult>> {
+        let e = match result {
+            Err(e) if self.compare => e,
+            result => return result,
+        };
+        if let Some(mismatch) = e.downcast_ref::<Mismatch>() {
+            log::warn!(
+                "database[{}] mismatch recorded as result:\n{}",
+                self.mysql_target,
+                mismatch
+            );
+            return Ok(Some(mismatch.result.clone()));
+        }
+        match e.downcast_ref::<mysql_async::Error>() {
+            Some(mysql_async::Error::Server(server_error)) => {
+                log::debug!("error recorded as result:\n{}", server_error);
+                Ok(Some(SqlResult {
+                    error: Some(server_error.code),
+                    ..Default::default()
+                }))
+            }
+            _ => Err(e),
+        }
+    }
+
+    /// 当前批次的结果
+    fn outcome(&self, statistics: &Statistics) -> Outcome {
+        let mut reads: Vec<Vec<Option<SqlResult>>> = self
+            .sqls_list
+            .iter()
+            .map(|sqls| vec![None; sqls.len()])
+            .collect();
+        if let Some(batch) = &statistics.cur_batch {
+            for (pos, (file_idx, sql_idx)) in batch.sql_idxs.iter().enumerate() {
+                // 自增id及警告数在不同数据库、不同执行顺序下本来就可能不同，
+                // 不参与比较
+                reads[*file_idx][*sql_idx] =
+                    batch
+                        .results
+                        .get(pos)
+                        .cloned()
+                        .flatten()
+                        .map(|result| SqlResult {
+                            last_insert_id: None,
+                            warnings: 0,
+                            ..result
+                        });
+            }
+        }
+        Outcome {
+            state: statistics.cur_final_state,
+            reads,
         }
     }
 
-    async fn recv(&self, state: &Arc<State>) -> Option<Vec<(usize, usize)>> {
+    /// 记录串行执行的结果，或者与串行执行的结果比较
+    fn judge_outcome(&self, statistics: &mut Statistics) {
+        if !self.record_outcomes && self.serial_outcomes.is_none() {
+            return;
+        }
+        let outcome = self.outcome(statistics);
+        if self.record_outcomes {
+            if !statistics.outcomes.contains(&outcome) {
+                statistics.outcomes.push(outcome);
+            }
+        } else if let Some(serial_outcomes) = &self.serial_outcomes {
+            if !serial_outcomes.contains(&outcome) {
+                log::debug!("batch is not equivalent to any serial order");
+                statistics
+                    .non_serializable_batches
+                    .push(statistics.cur_batch.clone().unwrap());
+            }
+        }
+    }
+
+    /// 输出当前批次捕获的结果，每个批次一行json
+    fn log_captured(&self, statistics: &Statistics) {
+        let batch = match &statistics.cur_batch {
+            Some(batch) => batch,
+            None => return,
+        };
+        let sqls: Vec<serde_json::Value> = batch
+            .sql_idxs
+            .iter()
+            .enumerate()
+            .map(|(pos, (file_idx, sql_idx))| {
+                let sql = &self.sqls_list[*file_idx][*sql_idx];
+                serde_json::json!({
+                    "at": sql.position(*file_idx),
+                    "sql": sql.text,
+                    "result": batch.results.get(pos).cloned().flatten(),
+                })
+            })
+            .collect();
+        let captured = serde_json::json!({
+            "database": self.mysql_target,
+            "batch": batch.idx.to_string(),
+            "sqls": sqls,
+        });
+        log::info!(target: CAPTURE_TARGET, "{}", captured);
+    }
+
+    async fn recv(&self, state: &Arc<State>) -> Option<Batch> {
         loop {
             let mut state_mut = state.lock().await;
             if state_mut.abort {
                 return None;
             }
-            match state_mut.queue.pop_front() {
+            // 广播或共享前缀时，只取出发给自己的批次
+            let pos = state_mut
+                .queue
+                .iter()
+                .position(|(_, batch)| batch.target.is_none_or(|target| target == self.id));
+            match pos.and_then(|pos| state_mut.queue.remove(pos)) {
                 Some((permit, r)) => {
                     // 把permit放回信号量
                     drop(permit);
@@ -256,6 +1011,342 @@ impl Worker {
         }
     }
 
+    /// 在同一个连接上按顺序执行当前批次，返回批次的执行时间
+    async fn run_batch_in_conn(
+        &self,
+        conn: &mut Conn,
+        statistics: &mut Statistics,
+    ) -> Result<Duration> {
+        let mut batch_time = Duration::from_nanos(0);
+        let batch = statistics.cur_batch.clone().unwrap();
+        for (pos, (file_idx, sql_idx)) in batch.sql_idxs.into_iter().enumerate() {
+            statistics.sql_amount += 1;
+            statistics.cur_batch_idx += 1;
+            let sql = &self.sqls_list[file_idx][sql_idx];
+            log::debug!("{:?}", sql.text);
+            if let Some(sleep) = sql.sleep {
+                tokio::time::delay_for(sleep).await;
+            }
+            let begin = Instant::now();
+            let captured = self.tolerate(session::execute(conn, sql, self.capturing()).await)?;
+            let sql_time = begin.elapsed();
+            statistics.record_sql(file_idx, sql_idx, sql_time);
+            statistics.record_result(pos, captured);
+            batch_time += sql_time;
+        }
+        Ok(batch_time)
+    }
+
+    /// 每个文件在各自的会话上执行当前批次，返回批次的执行时间
+    ///
+    /// 会话的sql超过`block_threshold`还没完成，就认为会话被阻塞，
+    /// 该会话后面的sql会延后执行，其它会话继续执行。所有sql都分派
+    /// 后，若剩余的会话都阻塞，则认为该批次发生了死锁。数据库检测到
+    /// 死锁时，批次也被记录为死锁，不再执行剩余的sql
+    async fn run_batch_in_sessions(
+        &self,
+        sessions: &mut [Session],
+        statistics: &mut Statistics,
+    ) -> Result<Duration> {
+        let mut batch_time = Duration::from_nanos(0);
+        let batch = statistics.cur_batch.clone().unwrap().sql_idxs;
+        let deadlock_amount = statistics.deadlock_amount;
+        for (pos, (file_idx, _)) in batch.iter().enumerate() {
+            statistics.sql_amount += 1;
+            let session = &mut sessions[*file_idx];
+            if session.is_idle() {
+                self.start_sql(session, &batch, pos, statistics).await;
+            } else {
+                // 会话还在执行前面的sql，按顺序延后执行
+                session.defer(pos);
+            }
+            batch_time += self.settle(sessions, &batch, statistics, false).await?;
+            if statistics.deadlock_amount > deadlock_amount {
+                return Ok(batch_time);
+            }
+        }
+        // 所有sql已经分派，等待剩余的会话执行完
+        batch_time += self.settle(sessions, &batch, statistics, true).await?;
+        if statistics.deadlock_amount == deadlock_amount
+            && sessions.iter().any(|session| !session.is_idle())
+        {
+            log::debug!("no session can make progress, deadlock detected!");
+            Self::record_deadlock(sessions, statistics).await;
+        }
+        Ok(batch_time)
+    }
+
+    /// 记录当前批次发生了死锁，并放弃所有会话
+    async fn record_deadlock(sessions: &mut [Session], statistics: &mut Statistics) {
+        statistics.deadlock_amount += 1;
+        statistics.deadlock_batches.push((
+            statistics.cur_batch.clone().unwrap(),
+            statistics.cur_blocked.clone(),
+        ));
+        session::abandon(sessions).await;
+    }
+
+    /// 在会话上开始执行批次中的一条sql，延后执行的sql在真正开始
+    /// 执行时才等待`-- @sleep`，等待期间其它会话可以继续执行
+    async fn start_sql(
+        &self,
+        session: &mut Session,
+        batch: &[(usize, usize)],
+        pos: usize,
+        statistics: &mut Statistics,
+    ) {
+        let (file_idx, sql_idx) = batch[pos];
+        let sql = &self.sqls_list[file_idx][sql_idx];
+        log::debug!("{:?}", sql.text);
+        if let Some(sleep) = sql.sleep {
+            tokio::time::delay_for(sleep).await;
+        }
+        statistics.cur_batch_idx = pos + 1;
+        session.start(pos, sql.clone(), self.capturing());
+    }
+
+    /// 等待执行中的sql，完成的会话继续执行延后的sql，直到剩余的sql
+    /// 都被阻塞，返回期间完成的sql的执行时间
+    ///
+    /// # Arguments
+    ///
+    /// * `wait_blocked` - 是否也等待已经被标记为阻塞的sql
+    async fn settle(
+        &self,
+        sessions: &mut [Session],
+        batch: &[(usize, usize)],
+        statistics: &mut Statistics,
+        wait_blocked: bool,
+    ) -> Result<Duration> {
+        let mut time = Duration::from_nanos(0);
+        loop {
+            // 只剩阻塞的sql时，仅检查它们是否已经完成
+            let threshold = if wait_blocked || sessions.iter().any(Session::is_settling) {
+                self.block_threshold
+            } else {
+                Duration::from_nanos(0)
+            };
+            match session::wait_any(sessions, threshold).await {
+                Some((session_idx, pos, result)) => {
+                    statistics.cur_batch_idx = pos + 1;
+                    let (sql_time, captured) = match result {
+                        Ok(result) => result,
+                        Err(e) if session::is_deadlock(&e) => {
+                            log::debug!("deadlock detected by database:\n{}", e);
+                            statistics.cur_blocked.push(pos + 1);
+                            Self::record_deadlock(sessions, statistics).await;
+                            return Ok(time);
+                        }
+                        Err(e) => (Duration::from_nanos(0), self.tolerate(Err(e))?),
+                    };
+                    let (file_idx, sql_idx) = batch[pos];
+                    statistics.record_sql(file_idx, sql_idx, sql_time);
+                    statistics.record_result(pos, captured);
+                    time += sql_time;
+                    let session = &mut sessions[session_idx];
+                    if let Some(pos) = session.next_deferred() {
+                        self.start_sql(session, batch, pos, statistics).await;
+                    }
+                }
+                None => {
+                    for session in sessions.iter_mut() {
+                        if let Some(pos) = session.mark_blocked() {
+                            log::debug!("sql at {} is blocked", pos + 1);
+                            statistics.blocked_amount += 1;
+                            statistics.cur_blocked.push(pos + 1);
+                        }
+                    }
+                    return Ok(time);
+                }
+            }
+        }
+    }
+
+    /// 重置并初始化数据库后执行一个批次
+    async fn run_batch(&self, batch: Batch, statistics: &mut Statistics) -> Result<()> {
+        // 循环里重新获取连接
+        let mut conn = self.mysql_pool.get_conn().await?;
+        // 先执行重置的sql，可以解决前一次执行程序产生了
+        // 脏数据的情况
+        conn.query_drop(&self.reset_sqls).await?;
+        // 再重新初始化sql
+        conn.query_drop(&self.init_sqls).await?;
+
+        statistics.cur_batch.replace(batch);
+        statistics.batch_amount += 1;
+        // 按索引从1开始
+        statistics.cur_batch_idx = 0;
+        statistics.cur_blocked.clear();
+        statistics.cur_final_state = None;
+        let deadlock_amount = statistics.deadlock_amount;
+        let batch_time = if self.session_per_file {
+            // 每个文件获取一个独立的连接
+            let mut sessions = Vec::with_capacity(self.sqls_list.len());
+            for file_idx in 0..self.sqls_list.len() {
+                let mut session_conn = self.mysql_pool.get_conn().await?;
+                if let Some(Some(sql)) = self.session_variables.get(file_idx) {
+                    session_conn.query_drop(sql).await?;
+                }
+                sessions.push(Session::new(session_conn));
+            }
+            let batch_time = self.run_batch_in_sessions(&mut sessions, statistics).await?;
+            // 回滚各会话未结束的事务，避免锁住下一次的重置
+            for session in sessions.iter_mut() {
+                if let Some(session_conn) = session.conn_mut() {
+                    session_conn.query_drop("ROLLBACK").await?;
+                }
+            }
+            batch_time
+        } else {
+            self.run_batch_in_conn(&mut conn, statistics).await?
+        };
+        // 发生死锁的批次中有被放弃的sql，已经单独报告，不检查最终状态
+        if statistics.deadlock_amount == deadlock_amount {
+            self.check_final_state(&mut conn, statistics).await?;
+        }
+        // 统计执行时间
+        statistics.time += batch_time;
+        if batch_time > statistics.slowest_batch_time {
+            statistics.slowest_batch_time = batch_time;
+            statistics
+                .slowest_batch
+                .replace(statistics.cur_batch.as_ref().unwrap().clone());
+        }
+        // 返回连接到池子，避免由于等待sql过久，导致连接没有
+        // 保活而失效
+        drop(conn);
+        Ok(())
+    }
+
+    /// sql是否为所在块的最后一条，没有设置块时每条sql都是一个块
+    fn ends_block(&self, file_idx: usize, sql_idx: usize) -> bool {
+        match self.blocks_list.get(file_idx) {
+            Some(blocks) => blocks.iter().any(|block| block.end == sql_idx + 1),
+            None => true,
+        }
+    }
+
+    /// 回滚到与上一个批次相同的前缀后执行当前批次的剩余部分
+    ///
+    /// 只在后续批次可能分叉的位置保存savepoint，即执行完一个块后还有
+    /// 至少两个文件有剩余的sql。sql的错误回滚了整个事务时，savepoint
+    /// 都已失效，批次结束后丢弃前缀，下一个批次重新开始事务
+    async fn run_batch_sharing_prefix(
+        &self,
+        prefix: &mut Option<Prefix>,
+        batch: Batch,
+        statistics: &mut Statistics,
+    ) -> Result<()> {
+        if prefix.is_none() {
+            let mut conn = self.mysql_pool.get_conn().await?;
+            conn.query_drop(&self.reset_sqls).await?;
+            conn.query_drop(&self.init_sqls).await?;
+            conn.query_drop("BEGIN").await?;
+            conn.query_drop(format!("SAVEPOINT {}0", SAVEPOINT_PREFIX))
+                .await
+                .map_err(|e| {
+                    Error::msg(format!(
+                        "Savepoint is not supported, please run without --{}:\n{}",
+                        arg::SHARE_PREFIX,
+                        e
+                    ))
+                })?;
+            prefix.replace(Prefix {
+                conn,
+                sql_idxs: vec![],
+                savepoints: vec![0],
+                results: vec![],
+            });
+        }
+        let shared = prefix.as_mut().unwrap();
+        let common = shared
+            .sql_idxs
+            .iter()
+            .zip(batch.sql_idxs.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        // 回滚到不超过相同前缀的最后一个savepoint
+        let common = *shared
+            .savepoints
+            .iter()
+            .rev()
+            .find(|&&savepoint| savepoint <= common)
+            .unwrap();
+        if common < shared.sql_idxs.len() {
+            shared
+                .conn
+                .query_drop(format!("ROLLBACK TO SAVEPOINT {}{}", SAVEPOINT_PREFIX, common))
+                .await?;
+            shared.sql_idxs.truncate(common);
+            shared.results.truncate(common);
+            shared.savepoints.retain(|&savepoint| savepoint <= common);
+        }
+
+        let sql_idxs = batch.sql_idxs.clone();
+        // 最后只剩一个文件的sql时，后续批次不会再分叉
+        let last_file_idx = sql_idxs.last().map(|&(file_idx, _)| file_idx);
+        let tail = sql_idxs
+            .iter()
+            .rposition(|&(file_idx, _)| Some(file_idx) != last_file_idx)
+            .map_or(0, |pos| pos + 1);
+        statistics.cur_batch.replace(batch);
+        // 前缀中的sql不再执行，沿用上一个批次捕获的结果
+        for (pos, captured) in shared.results.iter().enumerate() {
+            statistics.record_result(pos, captured.clone());
+        }
+        statistics.batch_amount += 1;
+        statistics.shared_sql_amount += common;
+        // 按索引从1开始，前缀中的sql已经执行
+        statistics.cur_batch_idx = common;
+        statistics.cur_blocked.clear();
+        statistics.cur_final_state = None;
+        let mut batch_time = Duration::from_nanos(0);
+        let mut rolled_back = false;
+        for (pos, (file_idx, sql_idx)) in sql_idxs.into_iter().enumerate().skip(common) {
+            statistics.sql_amount += 1;
+            statistics.cur_batch_idx += 1;
+            let sql = &self.sqls_list[file_idx][sql_idx];
+            log::debug!("{:?}", sql.text);
+            if let Some(sleep) = sql.sleep {
+                tokio::time::delay_for(sleep).await;
+            }
+            let begin = Instant::now();
+            let result = session::execute(&mut shared.conn, sql, self.capturing()).await;
+            if session::rolls_back_transaction(&result, sql) {
+                log::debug!("transaction rolled back, prefix will be reset");
+                rolled_back = true;
+            }
+            let captured = self.tolerate(result)?;
+            let sql_time = begin.elapsed();
+            statistics.record_sql(file_idx, sql_idx, sql_time);
+            statistics.record_result(pos, captured.clone());
+            batch_time += sql_time;
+            shared.sql_idxs.push((file_idx, sql_idx));
+            shared.results.push(captured);
+            let executed = shared.sql_idxs.len();
+            if !rolled_back && executed < tail && self.ends_block(file_idx, sql_idx) {
+                shared
+                    .conn
+                    .query_drop(format!("SAVEPOINT {}{}", SAVEPOINT_PREFIX, executed))
+                    .await?;
+                shared.savepoints.push(executed);
+            }
+        }
+        self.check_final_state(&mut shared.conn, statistics).await?;
+        statistics.time += batch_time;
+        if batch_time > statistics.slowest_batch_time {
+            statistics.slowest_batch_time = batch_time;
+            statistics
+                .slowest_batch
+                .replace(statistics.cur_batch.as_ref().unwrap().clone());
+        }
+        if rolled_back {
+            let mut shared = prefix.take().unwrap();
+            shared.conn.query_drop("ROLLBACK").await?;
+        }
+        Ok(())
+    }
+
     pub async fn run_with_error(
         &self,
         state: &Arc<State>,
@@ -266,6 +1357,8 @@ impl Worker {
         conn.query_drop(&self.init_sqls).await?;
         drop(conn);
 
+        // 共享前缀执行时保持的事务
+        let mut prefix = None;
         loop {
             let result = match self.recv(state).await {
                 Some(r) => r,
@@ -276,51 +1369,74 @@ impl Worker {
                 }
             };
 
-            log::debug!("One batch generated!");
-            // 循环里重新获取连接
-            let mut conn = self.mysql_pool.get_conn().await?;
-            // 先执行重置的sql，可以解决前一次执行程序产生了
-            // 脏数据的情况
-            conn.query_drop(&self.reset_sqls).await?;
-            // 再重新初始化sql
-            conn.query_drop(&self.init_sqls).await?;
-
-            statistics.cur_batch.replace(result);
-            statistics.batch_amount += 1;
-            // 按索引从1开始
-            statistics.cur_batch_idx = 0;
-            let mut batch_time = Duration::from_nanos(0);
-            for (file_idx, sql_idx) in statistics.cur_batch.as_ref().unwrap() {
-                statistics.sql_amount += 1;
-                statistics.cur_batch_idx += 1;
-                let sql = &self.sqls_list[*file_idx][*sql_idx];
-                log::debug!("{:?}", sql);
-                let begin = Instant::now();
-                conn.query_drop(sql).await?;
-                let sql_time = begin.elapsed();
-                if sql_time > statistics.slowest_sql_time {
-                    statistics.slowest_sql_time = sql_time;
-                    statistics.slowest_sql.replace((*file_idx, *sql_idx));
-                }
-                batch_time += sql_time;
+            log::debug!("Batch #{} received!", result.idx);
+            let seq = result.seq;
+            let deadlock_amount = statistics.deadlock_amount;
+            if self.share_prefix {
+                self.run_batch_sharing_prefix(&mut prefix, result, statistics)
+                    .await?;
+            } else {
+                self.run_batch(result, statistics).await?;
             }
-            // 统计执行时间
-            statistics.time += batch_time;
-            if batch_time > statistics.slowest_batch_time {
-                statistics.slowest_batch_time = batch_time;
-                statistics
-                    .slowest_batch
-                    .replace(statistics.cur_batch.as_ref().unwrap().clone());
+            // 发生死锁的批次中有被放弃的sql，已经单独报告
+            if statistics.deadlock_amount == deadlock_amount {
+                self.judge_outcome(statistics);
+            }
+            if self.capture {
+                self.log_captured(statistics);
+            }
+            let mut state_mut = state.lock().await;
+            if self.compare {
+                let outcome = self.outcome(statistics);
+                state_mut.compare(
+                    statistics.cur_batch.as_ref().unwrap(),
+                    &self.mysql_target,
+                    outcome,
+                );
+            }
+            // 发生死锁的批次没有按顺序执行完，不计入覆盖的先后顺序
+            if statistics.deadlock_amount == deadlock_amount {
+                if let Some(coverage) = state_mut.coverage.as_mut() {
+                    coverage.record(statistics.cur_batch.as_ref().unwrap());
+                }
             }
-            // 返回连接到池子，避免由于等待sql过久，导致连接没有
-            // 保活而失效
-            drop(conn);
+            state_mut.complete(seq, &self.mysql_target, statistics);
+        }
+        if let Some(mut prefix) = prefix {
+            prefix.conn.query_drop("ROLLBACK").await?;
         }
         Ok(())
     }
 
+    /// 串行执行一个样例批次（按文件顺序执行所有sql），返回包括重置
+    /// 及初始化在内的耗时，用于估算执行所有批次的时间
+    pub async fn time_sample_batch(&self) -> Result<Duration> {
+        let mut conn = self.mysql_pool.get_conn().await?;
+        conn.query_drop(&self.init_sqls).await?;
+        drop(conn);
+
+        // 按文件顺序串行执行的交错排列，在字典序中的索引为0
+        let mut sql_idxs = vec![];
+        for (file_idx, sqls) in self.sqls_list.iter().enumerate() {
+            for sql_idx in 0..sqls.len() {
+                sql_idxs.push((file_idx, sql_idx));
+            }
+        }
+        let begin = Instant::now();
+        self.run_batch(Batch::new(BigUint::from(0u8), sql_idxs), &mut Statistics::new())
+            .await?;
+        Ok(begin.elapsed())
+    }
+
+    pub fn mysql_target(&self) -> &str {
+        &self.mysql_target
+    }
+
     pub async fn run(self, state: Arc<State>) {
-        let mut statistics = Statistics::new();
+        let mut statistics = state
+            .lock()
+            .await
+            .take_resumed_statistics(&self.mysql_target);
         match self.run_with_error(&state, &mut statistics).await {
             Ok(_) => {}
             Err(e) => statistics.error = Some(e),
@@ -343,6 +1459,16 @@ pub struct ThreadPool {
     state: Arc<State>,
     _max_buffer_size: usize,
     worker_handles: Vec<JoinHandle<()>>,
+    /// 随机抽样时使用的种子，打印到统计信息，便于重现
+    seed: Option<u64>,
+    /// 偏序归约、对称归约及先后约束等剪掉的交错排列数量
+    pruned: Option<BigUint>,
+    /// 执行的场景的名字，打印到统计信息，便于区分测试集中的场景
+    scenario: Option<String>,
+    /// 检查最终状态的sql，用于打印最终状态
+    check_sqls: Vec<Sql>,
+    /// 每个数据库所有串行执行的结果，用于标记不可串行化的最终状态
+    serial_outcomes: Option<Arc<SerialOutcomes>>,
 }
 
 impl ThreadPool {
@@ -351,11 +1477,241 @@ impl ThreadPool {
             state: Arc::new(State::new(max_buffer_size)),
             _max_buffer_size: max_buffer_size,
             worker_handles: vec![],
+            seed: None,
+            pruned: None,
+            scenario: None,
+            check_sqls: vec![],
+            serial_outcomes: None,
+        }
+    }
+
+    /// 每个批次提交给每个Worker执行，而不是由Worker分摊
+    ///
+    /// # Arguments
+    ///
+    /// * `worker_amount` - Worker的数量
+    pub async fn enable_broadcast(&self, worker_amount: usize) {
+        let mut state_mut = self.state.lock().await;
+        state_mut.broadcast = true;
+        state_mut.worker_amount = worker_amount;
+    }
+
+    /// 开启比较模式，每个批次提交给每个Worker执行，并比较它们的结果
+    ///
+    /// # Arguments
+    ///
+    /// * `worker_amount` - Worker的数量，收到所有Worker的结果后比较
+    pub async fn enable_compare(&self, worker_amount: usize) {
+        self.enable_broadcast(worker_amount).await;
+        self.state.lock().await.compare = true;
+    }
+
+    /// 共享前缀时，按子树把批次分配给Worker，每个Worker按字典序执行
+    /// 自己的子树，相邻的批次才能共享前缀。广播时每个Worker本来就
+    /// 按顺序执行所有批次，不需要分配
+    ///
+    /// # Arguments
+    ///
+    /// * `worker_amount` - Worker的数量
+    pub async fn enable_share_prefix(&self, worker_amount: usize) {
+        let mut state_mut = self.state.lock().await;
+        if state_mut.broadcast {
+            return;
+        }
+        state_mut.share_prefix = true;
+        state_mut.worker_amount = worker_amount;
+        // 每个Worker至少可以排队一棵子树的批次，避免其它Worker空闲
+        self.state
+            .produce_permits
+            .add_permits(worker_amount * SUBTREE_BATCHES);
+    }
+
+    /// 记录每个数据库所有串行执行的结果
+    pub fn set_serial_outcomes(&mut self, serial_outcomes: Arc<SerialOutcomes>) {
+        self.serial_outcomes = Some(serial_outcomes);
+    }
+
+    /// 每个数据库串行执行得到的不同结果，需要在所有Worker结束后调用
+    pub async fn outcomes(&self) -> SerialOutcomes {
+        let state_mut = self.state.lock().await;
+        state_mut
+            .total_statistics
+            .iter()
+            .map(|(mysql_target, statistics)| (mysql_target.clone(), statistics.outcomes.clone()))
+            .collect()
+    }
+
+    /// 记录执行的场景的名字
+    pub fn set_scenario(&mut self, scenario: String) {
+        self.scenario = Some(scenario);
+    }
+
+    /// 记录检查最终状态的sql，开启检查时打印按最终状态分组的批次
+    pub fn set_check_sqls(&mut self, check_sqls: Vec<Sql>) {
+        self.check_sqls = check_sqls;
+    }
+
+    /// 记录随机抽样使用的种子
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+    }
+
+    /// 记录偏序归约、对称归约及先后约束等剪掉的交错排列数量
+    pub fn set_pruned(&mut self, pruned: BigUint) {
+        self.pruned = Some(pruned);
+    }
+
+    /// 开启两两顺序覆盖的统计，批次完成后记录它覆盖的先后顺序
+    ///
+    /// # Arguments
+    ///
+    /// * `sqls_list` - 每个文件的sql
+    /// * `blocks_list` - 每个文件的块
+    pub async fn enable_coverage(&self, sqls_list: &[Vec<Sql>], blocks_list: &[Vec<Range<usize>>]) {
+        let rows = blocks_list
+            .iter()
+            .zip(sqls_list.iter())
+            .map(|(blocks, sqls)| blocks.iter().map(|block| sqls[block.start].row).collect())
+            .collect();
+        let block_idxs = blocks_list
+            .iter()
+            .zip(sqls_list.iter())
+            .map(|(blocks, sqls)| {
+                let mut block_idxs = vec![0; sqls.len()];
+                for (block_idx, block) in blocks.iter().enumerate() {
+                    for sql_idx in block.clone() {
+                        block_idxs[sql_idx] = block_idx;
+                    }
+                }
+                block_idxs
+            })
+            .collect();
+        let sizes = blocks_list.iter().map(Vec::len).collect();
+        self.state.lock().await.coverage = Some(Coverage {
+            coverage: PairCoverage::new(sizes),
+            rows,
+            block_idxs,
+        });
+    }
+
+    /// 打印不同文件的块两两之间出现过的先后顺序，每两个文件打印一个
+    /// 矩阵，行为前一个文件的块，列为后一个文件的块
+    fn print_coverage(coverage: &PairCoverage, rows: &[Vec<usize>]) {
+        log::info!(
+            "Pairwise ordering coverage: {} of {} orders between statements of different files",
+            coverage.covered_amount(),
+            coverage.pair_amount()
+        );
+        let sizes = coverage.sizes();
+        for i in 0..sizes.len() {
+            for j in i + 1..sizes.len() {
+                let mut lines = vec![format!(
+                    "file {} \\ file {} (<: file {} first, >: file {} first, <>: both, -: none)",
+                    i + 1,
+                    j + 1,
+                    i + 1,
+                    j + 1
+                )];
+                let header: Vec<String> = rows[j]
+                    .iter()
+                    .map(|row| format!("{:>3}", row + 1))
+                    .collect();
+                lines.push(format!("        {}", header.join("")));
+                for (x, row) in rows[i].iter().enumerate() {
+                    let cells: Vec<String> = (0..sizes[j])
+                        .map(|y| {
+                            let cell = match (
+                                coverage.is_covered((i, x), (j, y)),
+                                coverage.is_covered((j, y), (i, x)),
+                            ) {
+                                (true, true) => "<>",
+                                (true, false) => "<",
+                                (false, true) => ">",
+                                (false, false) => "-",
+                            };
+                            format!("{:>3}", cell)
+                        })
+                        .collect();
+                    lines.push(format!("row {:>3}:{}", row + 1, cells.join("")));
+                }
+                log::info!("{}", lines.join("\n"));
+            }
         }
     }
 
+    /// 开启检查点，执行过程中定期把进度及统计数据保存到`checkpoint_file`
+    pub async fn enable_checkpoint(
+        &self,
+        checkpoint_file: PathBuf,
+        sizes: Vec<usize>,
+        generation: Generation,
+    ) {
+        let mut state_mut = self.state.lock().await;
+        state_mut.checkpoint_file = Some(checkpoint_file);
+        state_mut.sizes = sizes;
+        state_mut.generation = generation;
+    }
+
+    /// 从检查点恢复进度及统计数据，已经完成的批次在提交时会被跳过
+    pub async fn resume(&self, checkpoint: Checkpoint) -> Result<()> {
+        let mut resumed_statistics = vec![];
+        for (mysql_target, snapshot) in checkpoint.statistics.iter() {
+            let statistics = serde_json::from_value(snapshot.clone())?;
+            resumed_statistics.push((mysql_target.clone(), statistics));
+        }
+        let mut state_mut = self.state.lock().await;
+        log::info!(
+            "resume from checkpoint, completed batches: {}",
+            checkpoint.progress.completed()
+        );
+        state_mut.progress = checkpoint.progress;
+        state_mut.statistics_snapshots = checkpoint.statistics;
+        state_mut.resumed_statistics = resumed_statistics;
+        Ok(())
+    }
+
+    /// 快照所有数据库最终的统计数据，并立即保存检查点，没有开启检查点
+    /// 时不做任何事，需要在所有Worker结束后调用
+    pub async fn save_checkpoint(&self) -> Result<()> {
+        let mut state_mut = self.state.lock().await;
+        if state_mut.checkpoint_file.is_none() {
+            return Ok(());
+        }
+        let total_statistics = std::mem::take(&mut state_mut.total_statistics);
+        for (mysql_target, statistics) in total_statistics.iter() {
+            state_mut.snapshot(mysql_target, statistics);
+        }
+        state_mut.total_statistics = total_statistics;
+        state_mut.save_checkpoint()
+    }
+
+    /// 收到Ctrl-C时中断线程池，Worker执行完当前的批次后结束
+    pub fn interrupt_on_ctrl_c(&self) {
+        let state = Arc::clone(&self.state);
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                log::info!("interrupted, waiting running batches to be finished.");
+                state.lock().await.interrupt().await;
+            }
+        });
+    }
+
     /// 把生成好的sql索引批次提交到线程池
-    pub async fn submit(&self, sql_idx_batch: Vec<(usize, usize)>) -> Result<()> {
+    pub async fn submit(&self, mut sql_idx_batch: Batch) -> Result<()> {
+        {
+            // 按提交顺序分配编号，跳过检查点里已经完成的批次
+            let mut state_mut = self.state.lock().await;
+            sql_idx_batch.seq = state_mut.next_seq;
+            state_mut.next_seq += 1;
+            if state_mut.progress.is_completed(sql_idx_batch.seq) {
+                log::trace!("sql idx batch skipped.");
+                // 检查点中已经完成的批次，也计入覆盖的先后顺序
+                if let Some(coverage) = state_mut.coverage.as_mut() {
+                    coverage.record(&sql_idx_batch);
+                }
+                return Ok(());
+            }
+        }
         // 先获取permit，再放到队列
         let permit = self.state.produce_permits.clone().acquire_owned().await;
         // 获取锁
@@ -364,6 +1720,24 @@ impl ThreadPool {
             // 线程池被关闭
             return Err(Error::msg("ThreadPool is not running"));
         }
+        state_mut
+            .progress
+            .submit(sql_idx_batch.seq, &sql_idx_batch.idx);
+        if state_mut.broadcast {
+            let worker_amount = state_mut.worker_amount;
+            drop(state_mut);
+            return self
+                .submit_to_all(permit, sql_idx_batch, worker_amount)
+                .await;
+        }
+        if state_mut.share_prefix {
+            sql_idx_batch.target = Some(state_mut.assign_subtree(&sql_idx_batch));
+            state_mut.queue.push_back((permit, sql_idx_batch));
+            // 不知道哪个等待的Worker是目标，通知所有Worker
+            state_mut.notify_all();
+            log::trace!("sql idx batch pushed.");
+            return Ok(());
+        }
         state_mut.queue.push_back((permit, sql_idx_batch));
         // 通知一个阻塞的Worker
         if let Some(signal) = state_mut.waiting_worker_signals.pop_front() {
@@ -373,7 +1747,36 @@ impl ThreadPool {
         Ok(())
     }
 
-    pub fn add_worker(&mut self, worker: Worker) {
+    /// 把批次的拷贝提交给每个Worker，第一份使用已经
+    /// 获取的permit，其它的重新获取
+    async fn submit_to_all(
+        &self,
+        permit: OwnedSemaphorePermit,
+        batch: Batch,
+        worker_amount: usize,
+    ) -> Result<()> {
+        let mut permit = Some(permit);
+        for target in 0..worker_amount {
+            let permit = match permit.take() {
+                Some(permit) => permit,
+                None => self.state.produce_permits.clone().acquire_owned().await,
+            };
+            let mut state_mut = self.state.lock().await;
+            if !state_mut.running {
+                return Err(Error::msg("ThreadPool is not running"));
+            }
+            let mut batch = batch.clone();
+            batch.target = Some(target);
+            state_mut.queue.push_back((permit, batch));
+            // 不知道哪个等待的Worker是目标，通知所有Worker
+            state_mut.notify_all();
+        }
+        log::trace!("sql idx batch pushed to all workers.");
+        Ok(())
+    }
+
+    pub fn add_worker(&mut self, mut worker: Worker) {
+        worker.id = self.worker_handles.len();
         let state = Arc::clone(&self.state);
         let handle = tokio::spawn(async move {
             worker.run(state).await;
@@ -397,10 +1800,161 @@ impl ThreadPool {
                 }
             }
         }
+        // 检查点里没有对应Worker的统计数据，也需要保留
+        let mut state_mut = self.state.lock().await;
+        let resumed_statistics: Vec<_> = state_mut.resumed_statistics.drain(..).collect();
+        state_mut.total_statistics.extend(resumed_statistics);
     }
 
-    pub async fn print_statistic(&self, sqls_list: &Vec<Vec<String>>) {
+    /// 执行的批次总数，及执行出错的数据库和错误，需要在所有Worker
+    /// 结束后调用
+    pub async fn outcome(&self) -> (usize, Vec<(String, String)>) {
         let state_mut = self.state.lock().await;
+        let mut batch_amount = 0;
+        let mut errors = vec![];
+        for (mysql_target, statistics) in state_mut.total_statistics.iter() {
+            batch_amount += statistics.batch_amount;
+            if let Some(error) = &statistics.error {
+                errors.push((mysql_target.clone(), error.to_string()));
+            }
+        }
+        if !state_mut.divergences.is_empty() {
+            let mysql_targets: Vec<&str> = state_mut
+                .total_statistics
+                .iter()
+                .map(|(mysql_target, _)| mysql_target.as_str())
+                .collect();
+            errors.push((
+                mysql_targets.join(", "),
+                format!(
+                    "{} batches diverge between databases",
+                    state_mut.divergences.len()
+                ),
+            ));
+        }
+        (batch_amount, errors)
+    }
+
+    /// 合并所有数据库得到的最终状态，按批次数量从多到少排序，需要在
+    /// 所有Worker结束后调用
+    pub async fn final_states(&self) -> Vec<FinalState> {
+        Self::merge_final_states(&self.state.lock().await.total_statistics)
+    }
+
+    fn merge_final_states(total_statistics: &[(String, Statistics)]) -> Vec<FinalState> {
+        let mut final_states: Vec<FinalState> = vec![];
+        for (_, statistics) in total_statistics.iter() {
+            for state in statistics.final_states.iter() {
+                match final_states.iter_mut().find(|s| s.hash == state.hash) {
+                    Some(s) => s.amount += state.amount,
+                    None => final_states.push(state.clone()),
+                }
+            }
+        }
+        final_states.sort_by_key(|state| Reverse(state.amount));
+        final_states
+    }
+
+    /// 找出每个数据库得到的，该数据库串行执行不能得到的最终状态，
+    /// 返回最终状态的哈希值及得到它的数据库
+    fn unreachable_final_states(
+        total_statistics: &[(String, Statistics)],
+        serial_outcomes: &[(String, Vec<Outcome>)],
+    ) -> HashMap<u64, Vec<String>> {
+        let mut unreachable: HashMap<u64, Vec<String>> = HashMap::new();
+        for (mysql_target, statistics) in total_statistics.iter() {
+            let outcomes = serial_outcomes
+                .iter()
+                .find(|(target, _)| target == mysql_target)
+                .map(|(_, outcomes)| &outcomes[..])
+                .unwrap_or(&[]);
+            for state in statistics.final_states.iter() {
+                if !outcomes.iter().any(|o| o.state == Some(state.hash)) {
+                    unreachable
+                        .entry(state.hash)
+                        .or_default()
+                        .push(mysql_target.clone());
+                }
+            }
+        }
+        unreachable
+    }
+
+    /// 打印每种最终状态，得到它的批次数量，及一个得到它的批次，
+    /// 有串行执行的结果时，标记在得到它的数据库上串行执行不能得到的
+    /// 最终状态
+    fn print_final_states(
+        final_states: &[FinalState],
+        sqls_list: &[Vec<Sql>],
+        check_sqls: &[Sql],
+        unreachable: &HashMap<u64, Vec<String>>,
+    ) {
+        let total: usize = final_states.iter().map(|state| state.amount).sum();
+        log::info!(
+            "Final states: {} distinct states of {} batches",
+            final_states.len(),
+            total
+        );
+        for (i, state) in final_states.iter().enumerate() {
+            let serializable = match unreachable.get(&state.hash) {
+                Some(mysql_targets) => format!(
+                    " [not reachable by any serial order in database[{}]]",
+                    mysql_targets.join(", ")
+                ),
+                None => String::new(),
+            };
+            log::info!(
+                "Final state #{} ({:016x}){}, reached by {} batches, sample batch #{}:",
+                i + 1,
+                state.hash,
+                serializable,
+                state.amount,
+                state.sample.idx
+            );
+            Statistics::print_sql_batch(sqls_list, &state.sample, None, &[]);
+            for (sql, rows) in check_sqls.iter().zip(state.rows.iter()) {
+                let rows: Vec<String> = rows
+                    .iter()
+                    .map(|row| format!("({})", row.join(", ")))
+                    .collect();
+                log::info!("{} => {}", sql.text, rows.join(", "));
+            }
+        }
+    }
+
+    pub async fn print_statistic(&self, sqls_list: &Vec<Vec<Sql>>) {
+        let state_mut = self.state.lock().await;
+        if let Some(scenario) = &self.scenario {
+            log::info!("Scenario: {}", scenario);
+        }
+        if let Some(seed) = self.seed {
+            log::info!("Batches are sampled randomly with seed: {}", seed);
+        }
+        if let Some(pruned) = &self.pruned {
+            log::info!(
+                "Interlace permutations pruned by reductions and constraints: {}",
+                pruned
+            );
+        }
+        if let Some(coverage) = &state_mut.coverage {
+            Self::print_coverage(&coverage.coverage, &coverage.rows);
+        }
+        if let Some(serial_outcomes) = &self.serial_outcomes {
+            for (mysql_target, outcomes) in serial_outcomes.iter() {
+                log::info!(
+                    "Distinct outcomes of serial orders in database[{}]: {}",
+                    mysql_target,
+                    outcomes.len()
+                );
+            }
+        }
+        if state_mut.checkpoint_file.is_some() {
+            log::info!(
+                "Completed batches in order: {}, Unfinished batches: {}",
+                state_mut.progress.completed(),
+                state_mut.progress.in_flight()
+            );
+        }
         for (mysql_target, statistics) in state_mut.total_statistics.iter() {
             log::info!(
                 "=============start statistics of database[{}]=============",
@@ -412,5 +1966,24 @@ impl ThreadPool {
                 mysql_target
             );
         }
+        if state_mut.compare {
+            log::info!(
+                "Batches diverging between databases: {}",
+                state_mut.divergences.len()
+            );
+            for divergence in state_mut.divergences.iter() {
+                divergence.print(sqls_list);
+            }
+        }
+        if !self.check_sqls.is_empty() {
+            let final_states = Self::merge_final_states(&state_mut.total_statistics);
+            let unreachable = match &self.serial_outcomes {
+                Some(serial_outcomes) => {
+                    Self::unreachable_final_states(&state_mut.total_statistics, serial_outcomes)
+                }
+                None => HashMap::new(),
+            };
+            Self::print_final_states(&final_states, sqls_list, &self.check_sqls, &unreachable);
+        }
     }
 }
@@ -0,0 +1,187 @@
+use anyhow::{Error, Result};
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::algo::Pruner;
+
+/// 不同文件的sql之间的先后约束，`before`必须在`after`之前执行
+///
+/// sql位置都是从0开始的(文件索引, 文件中的位置)
+#[derive(Clone, Copy, Debug)]
+pub struct HappensBefore {
+    pub before: (usize, usize),
+    pub after: (usize, usize),
+}
+
+/// 找出sql所在的块
+fn block_of(
+    blocks_list: &[Vec<Range<usize>>],
+    (file_idx, sql_idx): (usize, usize),
+) -> Result<usize> {
+    blocks_list
+        .get(file_idx)
+        .and_then(|blocks| blocks.iter().position(|block| block.contains(&sql_idx)))
+        .ok_or_else(|| {
+            Error::msg(format!(
+                "There is no sql at file {} row {}",
+                file_idx + 1,
+                sql_idx + 1
+            ))
+        })
+}
+
+/// 生成只允许满足所有先后约束的交错排列的剪枝条件
+///
+/// 约束的sql会换算成所在的块，同一个文件的约束与文件中的顺序
+/// 矛盾时返回错误，不同文件的约束形成环时没有满足的交错排列
+///
+/// # Arguments
+///
+/// * `constraints` - 所有先后约束
+/// * `blocks_list` - 每个文件的块，交错排列以块为单位
+pub fn into_pruner(
+    constraints: &[HappensBefore],
+    blocks_list: &[Vec<Range<usize>>],
+) -> Result<Pruner> {
+    // 每个块必须在哪些块之后执行
+    let mut afters: Vec<Vec<Vec<(usize, usize)>>> = blocks_list
+        .iter()
+        .map(|blocks| vec![vec![]; blocks.len()])
+        .collect();
+    for constraint in constraints {
+        let before = (
+            constraint.before.0,
+            block_of(blocks_list, constraint.before)?,
+        );
+        let after = (constraint.after.0, block_of(blocks_list, constraint.after)?);
+        if before.0 == after.0 {
+            // 同一个文件的sql总是按顺序执行
+            if before.1 >= after.1 {
+                return Err(Error::msg(format!(
+                    "Constraint between sql #{} and sql #{} of file {} conflicts with the order of sqls in the file",
+                    constraint.before.1 + 1,
+                    constraint.after.1 + 1,
+                    constraint.before.0 + 1
+                )));
+            }
+            continue;
+        }
+        afters[after.0][after.1].push(before);
+    }
+    Ok(Arc::new(move |_, curs, i| {
+        // 队列当前位置之前的块都已经执行
+        afters[i][curs[i]]
+            .iter()
+            .all(|&(file_idx, block_idx)| curs[file_idx] > block_idx)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algo::InterlacePermutations;
+
+    /// 文件0的第2、3条sql是一个块，其它sql各自是一个块
+    fn blocks_list() -> Vec<Vec<Range<usize>>> {
+        vec![vec![0..1, 1..3], vec![0..1, 1..2, 2..3], vec![0..1, 1..2]]
+    }
+
+    fn pruned(constraints: &[HappensBefore]) -> Vec<Vec<(usize, usize)>> {
+        let blocks_list = blocks_list();
+        let sizes = blocks_list.iter().map(Vec::len).collect();
+        let pruner = into_pruner(constraints, &blocks_list).unwrap();
+        InterlacePermutations::new(sizes)
+            .with_pruner(pruner)
+            .collect()
+    }
+
+    /// 从所有交错排列中逐个检查约束得到的结果
+    fn filtered(constraints: &[HappensBefore]) -> Vec<Vec<(usize, usize)>> {
+        let blocks_list = blocks_list();
+        let sizes = blocks_list.iter().map(Vec::len).collect();
+        let pos = |batch: &[(usize, usize)], (file_idx, sql_idx): (usize, usize)| {
+            let block_idx = block_of(&blocks_list, (file_idx, sql_idx)).unwrap();
+            batch
+                .iter()
+                .position(|&step| step == (file_idx, block_idx))
+                .unwrap()
+        };
+        InterlacePermutations::new(sizes)
+            .filter(|batch| {
+                constraints
+                    .iter()
+                    .all(|c| pos(batch, c.before) < pos(batch, c.after))
+            })
+            .collect()
+    }
+
+    fn hb(before: (usize, usize), after: (usize, usize)) -> HappensBefore {
+        HappensBefore { before, after }
+    }
+
+    #[test]
+    fn test_matches_brute_force() {
+        let cases = [
+            vec![],
+            vec![hb((0, 0), (1, 2))],
+            vec![hb((1, 2), (0, 0))],
+            // 约束块中的第二条sql，等价于约束整个块
+            vec![hb((0, 2), (1, 0))],
+            vec![hb((2, 1), (1, 0)), hb((1, 1), (0, 1))],
+            vec![hb((0, 0), (1, 1)), hb((1, 1), (2, 0)), hb((0, 0), (2, 1))],
+            // 同一个文件中符合顺序的约束不影响结果
+            vec![hb((1, 0), (1, 2)), hb((2, 0), (0, 1))],
+        ];
+        for constraints in cases.iter() {
+            let pruned = pruned(constraints);
+            assert!(!pruned.is_empty(), "constraints: {:?}", constraints);
+            assert_eq!(
+                pruned,
+                filtered(constraints),
+                "constraints: {:?}",
+                constraints
+            );
+        }
+        assert_eq!(pruned(&[]).len(), 210);
+    }
+
+    #[test]
+    fn test_cycle() {
+        let cases = [
+            vec![hb((0, 0), (1, 0)), hb((1, 0), (0, 0))],
+            vec![hb((0, 1), (1, 1)), hb((1, 1), (2, 1)), hb((2, 1), (0, 0))],
+            // 块内的sql不能被其它文件的sql隔开
+            vec![hb((0, 1), (1, 0)), hb((1, 0), (0, 2))],
+        ];
+        for constraints in cases.iter() {
+            assert!(
+                filtered(constraints).is_empty(),
+                "constraints: {:?}",
+                constraints
+            );
+            assert!(
+                pruned(constraints).is_empty(),
+                "constraints: {:?}",
+                constraints
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        let blocks_list = blocks_list();
+        // 与文件中的顺序矛盾，包括同一个块中的sql
+        for constraint in [hb((1, 2), (1, 0)), hb((1, 1), (1, 1)), hb((0, 1), (0, 2))].iter() {
+            assert!(
+                into_pruner(&[*constraint], &blocks_list).is_err(),
+                "constraint: {:?}",
+                constraint
+            );
+        }
+        // 超出范围的文件或sql
+        for constraint in [hb((0, 3), (1, 0)), hb((0, 0), (3, 0)), hb((2, 0), (1, 9))].iter() {
+            let err = into_pruner(&[*constraint], &blocks_list).err().unwrap();
+            assert!(err.to_string().starts_with("There is no sql at file "));
+        }
+    }
+}
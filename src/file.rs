@@ -1,39 +1,945 @@
-use anyhow::Result;
+use anyhow::{Error, Result};
+use futures::future::{BoxFuture, FutureExt};
 use log::{log_enabled, Level::Debug};
-use std::path::Path;
-use tokio::fs::File;
-use tokio::io::BufReader;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::fs::{self, File};
 use tokio::prelude::*;
 
+/// 表示标准输入的文件名
+const STDIN: &str = "-";
+/// 标准输入是否已经被读取
+static STDIN_READ: AtomicBool = AtomicBool::new(false);
+
 pub async fn read_sqls_by_line(file: impl AsRef<Path>) -> Result<Vec<String>> {
     let mut path = None;
     if log_enabled!(Debug) {
         path = Some(String::from(file.as_ref().to_string_lossy()));
     }
-    let mut lines = BufReader::new(File::open(file).await?).lines();
-    let mut result = vec![];
-    while let Some(line) = lines.next_line().await? {
-        result.push(line);
-    }
+    let content = read_sqls(file).await?;
+    let result: Vec<String> = content.lines().map(String::from).collect();
     if log_enabled!(Debug) {
         log::debug!("read from file by line[{:?}]:\n{:?}", path, result);
     }
     Ok(result)
 }
 
-pub async fn read_sqls(file: impl AsRef<Path>) -> Result<String> {
+/// 拆分后的sql文件，连续的多条sql可以组成一个块
+pub struct SqlFile {
+    pub sqls: Vec<Sql>,
+    /// 每个块包含的sql在`sqls`中的范围，不在块中的sql单独作为一个块，
+    /// 交错排列时以块为单位
+    pub blocks: Vec<Range<usize>>,
+}
+
+/// sql文件中的一条sql
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sql {
+    /// 去掉了结尾分隔符的sql
+    pub text: String,
+    /// sql开始的行，从0开始，在被包含的文件中时为该文件中的行
+    pub row: usize,
+    /// sql所在的被包含的文件，直接在sql文件中时为None
+    pub file: Option<String>,
+    /// `-- @expect-error`指定的期望错误码
+    pub expect_error: Option<u16>,
+    /// `-- @expect-rows`指定的期望行数，查询为返回的行数，其它为影响的行数
+    pub expect_rows: Option<u64>,
+    /// `-- @sleep`指定的执行前等待的时间
+    pub sleep: Option<Duration>,
+    /// `-- @label`指定的标签，统计数据中代替文件及行号
+    pub label: Option<String>,
+}
+
+impl Sql {
+    /// sql在统计数据中的位置，有标签时为标签
+    pub fn position(&self, file_idx: usize) -> String {
+        match &self.label {
+            Some(label) => label.clone(),
+            None => match &self.file {
+                Some(file) => format!("(file {}, {} row {})", file_idx + 1, file, self.row + 1),
+                None => format!("(file {}, row {})", file_idx + 1, self.row + 1),
+            },
+        }
+    }
+}
+
+/// sql文件拆分后的单元
+enum Token {
+    Sql(Sql),
+    /// sql之间以`-- @`开头的注释，为`@`之后的内容
+    Directive {
+        text: String,
+        row: usize,
+        /// 指令所在的被包含的文件，直接在sql文件中时为None
+        file: Option<String>,
+    },
+    /// sql之间的`source file`、`\. file`或`-- @include file`
+    Include {
+        path: String,
+        row: usize,
+        /// 所在行在内容中的字符范围，不包括换行
+        span: Range<usize>,
+    },
+}
+
+impl Token {
+    /// 记录展开后的单元来自哪个被包含的文件，嵌套包含时保留最内层的文件
+    fn set_file(&mut self, included: &str) {
+        let file = match self {
+            Token::Sql(sql) => &mut sql.file,
+            Token::Directive { file, .. } => file,
+            Token::Include { .. } => return,
+        };
+        if file.is_none() {
+            *file = Some(included.to_owned());
+        }
+    }
+}
+
+/// 按MySQL客户端的规则把sql文件拆分为多条sql
+struct Splitter {
+    chars: Vec<char>,
+    pos: usize,
+    /// 当前所在的行，从0开始
+    row: usize,
+    delimiter: Vec<char>,
+    tokens: Vec<Token>,
+    /// 当前的sql，为空时不在sql中
+    sql: String,
+    sql_row: usize,
+}
+
+impl Splitter {
+    fn new(content: &str) -> Self {
+        Splitter {
+            chars: content.chars().collect(),
+            pos: 0,
+            row: 0,
+            delimiter: vec![';'],
+            tokens: vec![],
+            sql: String::new(),
+            sql_row: 0,
+        }
+    }
+
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    /// 是否`--`加空白或者`#`开始的单行注释
+    fn is_line_comment(&self) -> bool {
+        match (self.peek(0), self.peek(1), self.peek(2)) {
+            (Some('#'), _, _) => true,
+            (Some('-'), Some('-'), None) => true,
+            (Some('-'), Some('-'), Some(c)) => c.is_whitespace(),
+            _ => false,
+        }
+    }
+
+    fn is_block_comment(&self) -> bool {
+        self.peek(0) == Some('/') && self.peek(1) == Some('*')
+    }
+
+    fn is_delimiter(&self) -> bool {
+        self.chars[self.pos..].starts_with(&self.delimiter)
+    }
+
+    /// 取出从当前位置到行尾的内容，不包括换行
+    fn take_line(&mut self) -> String {
+        let begin = self.pos;
+        while self.pos < self.chars.len() && self.chars[self.pos] != '\n' {
+            self.pos += 1;
+        }
+        self.chars[begin..self.pos].iter().collect()
+    }
+
+    /// 取出一个字符，并记录换行
+    fn take_char(&mut self) -> char {
+        let c = self.chars[self.pos];
+        self.pos += 1;
+        if c == '\n' {
+            self.row += 1;
+        }
+        c
+    }
+
+    fn take_block_comment(&mut self) -> Result<String> {
+        let row = self.row;
+        let mut comment = String::new();
+        comment.push(self.take_char());
+        comment.push(self.take_char());
+        loop {
+            if self.pos >= self.chars.len() {
+                return Err(Error::msg(format!("Unclosed comment at line {}", row + 1)));
+            }
+            if self.peek(0) == Some('*') && self.peek(1) == Some('/') {
+                comment.push(self.take_char());
+                comment.push(self.take_char());
+                return Ok(comment);
+            }
+            comment.push(self.take_char());
+        }
+    }
+
+    /// 取出引号中的内容，包括引号，单双引号中可以用反斜杠转义，
+    /// 所有引号都可以用两个引号表示引号本身
+    fn take_quoted(&mut self) -> Result<String> {
+        let row = self.row;
+        let quote = self.take_char();
+        let mut quoted = quote.to_string();
+        loop {
+            if self.pos >= self.chars.len() {
+                return Err(Error::msg(format!(
+                    "Unclosed quote {} at line {}",
+                    quote,
+                    row + 1
+                )));
+            }
+            let c = self.take_char();
+            quoted.push(c);
+            if c == '\\' && quote != '`' && self.pos < self.chars.len() {
+                quoted.push(self.take_char());
+            } else if c == quote {
+                if self.peek(0) != Some(quote) {
+                    return Ok(quoted);
+                }
+                quoted.push(self.take_char());
+            }
+        }
+    }
+
+    /// 在sql开始前，是否`source file`或`\. file`命令，返回文件路径
+    fn include_command(&self) -> Option<String> {
+        let end = self.chars[self.pos..]
+            .iter()
+            .position(|&c| c == '\n')
+            .map_or(self.chars.len(), |len| self.pos + len);
+        let line: String = self.chars[self.pos..end].iter().collect();
+        include_path(&line).map(str::to_owned)
+    }
+
+    /// 在sql开始前，是否`DELIMITER`命令
+    fn is_delimiter_command(&self) -> bool {
+        let keyword = "delimiter";
+        let len = keyword.len();
+        self.chars.len() > self.pos + len
+            && self.chars[self.pos..self.pos + len]
+                .iter()
+                .collect::<String>()
+                .eq_ignore_ascii_case(keyword)
+            && self.chars[self.pos + len].is_whitespace()
+            && self.chars[self.pos + len] != '\n'
+    }
+
+    fn finish_sql(&mut self) {
+        let text = self.sql.trim();
+        if !text.is_empty() {
+            self.tokens.push(Token::Sql(Sql {
+                text: text.to_owned(),
+                row: self.sql_row,
+                ..Default::default()
+            }));
+        }
+        self.sql.clear();
+    }
+
+    /// 在sql开始前，跳过空白及注释，处理`DELIMITER`命令及指令，
+    /// 返回是否处理了
+    fn skip_between_sqls(&mut self) -> Result<bool> {
+        let c = self.chars[self.pos];
+        if c.is_whitespace() {
+            self.take_char();
+        } else if self.is_line_comment() {
+            let row = self.row;
+            let begin = self.pos;
+            let comment = self.take_line();
+            let comment = comment.trim();
+            if let Some(path) = include_path(comment) {
+                self.tokens.push(Token::Include {
+                    path: path.to_owned(),
+                    row,
+                    span: begin..self.pos,
+                });
+            } else if let Some(directive) = comment.strip_prefix("--") {
+                if let Some(directive) = directive.trim_start().strip_prefix('@') {
+                    self.tokens.push(Token::Directive {
+                        text: directive.trim().to_owned(),
+                        row,
+                        file: None,
+                    });
+                }
+            }
+        } else if self.is_block_comment() && self.peek(2) != Some('!') {
+            self.take_block_comment()?;
+        } else if self.is_delimiter_command() {
+            let row = self.row;
+            let line = self.take_line();
+            let delimiter = line["delimiter".len()..].trim();
+            if delimiter.is_empty() {
+                return Err(Error::msg(format!("Empty delimiter at line {}", row + 1)));
+            }
+            self.delimiter = delimiter.chars().collect();
+        } else if let Some(path) = self.include_command() {
+            let row = self.row;
+            let begin = self.pos;
+            self.take_line();
+            self.tokens.push(Token::Include {
+                path,
+                row,
+                span: begin..self.pos,
+            });
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn split(mut self) -> Result<Vec<Token>> {
+        while self.pos < self.chars.len() {
+            if self.sql.trim().is_empty() {
+                self.sql.clear();
+                if self.skip_between_sqls()? {
+                    continue;
+                }
+                self.sql_row = self.row;
+            }
+            if self.is_delimiter() {
+                self.pos += self.delimiter.len();
+                self.finish_sql();
+                continue;
+            }
+            match self.chars[self.pos] {
+                '\'' | '"' | '`' => {
+                    let quoted = self.take_quoted()?;
+                    self.sql.push_str(&quoted);
+                }
+                _ if self.is_line_comment() => {
+                    let row = self.row;
+                    let comment = self.take_line();
+                    if comment
+                        .trim_start_matches('-')
+                        .trim_start()
+                        .starts_with('@')
+                    {
+                        return Err(Error::msg(format!(
+                            "Directive at line {} is inside a statement, the delimiter may be missing",
+                            row + 1
+                        )));
+                    }
+                    self.sql.push_str(&comment);
+                }
+                _ if self.is_block_comment() => {
+                    let comment = self.take_block_comment()?;
+                    self.sql.push_str(&comment);
+                }
+                _ => {
+                    let c = self.take_char();
+                    self.sql.push(c);
+                }
+            }
+        }
+        // 最后一条sql可以没有分隔符
+        self.finish_sql();
+        Ok(self.tokens)
+    }
+}
+
+/// 解析`50ms`、`2s`、`1m`格式的时间
+fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let unit_begin = s.find(|c: char| !c.is_ascii_digit())?;
+    let amount: u64 = s[..unit_begin].parse().ok()?;
+    match &s[unit_begin..] {
+        "ms" => Some(Duration::from_millis(amount)),
+        "s" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_secs(amount * 60)),
+        _ => None,
+    }
+}
+
+/// 把指令应用到它后面的sql上
+///
+/// # Arguments
+///
+/// * `sql` - 指令后面的sql
+/// * `directive` - `@`之后的内容
+fn apply_directive(sql: &mut Sql, directive: &str) -> Result<()> {
+    let mut parts = directive.splitn(2, char::is_whitespace);
+    let name = parts.next().unwrap_or("").to_lowercase();
+    let value = parts.next().unwrap_or("").trim();
+    let invalid = || Error::msg(format!("Invalid directive `@{}`", directive));
+    match name.as_str() {
+        "expect-error" => sql.expect_error = Some(value.parse().map_err(|_| invalid())?),
+        "expect-rows" => sql.expect_rows = Some(value.parse().map_err(|_| invalid())?),
+        "sleep" => sql.sleep = Some(parse_duration(value).ok_or_else(invalid)?),
+        "label" if !value.is_empty() => sql.label = Some(value.to_owned()),
+        _ => return Err(Error::msg(format!("Unknown directive `@{}`", directive))),
+    }
+    Ok(())
+}
+
+/// 读取sql文件，按MySQL客户端的规则拆分为多条sql，并展开其中
+/// 包含的文件，`-`表示从标准输入读取
+pub async fn read_sql_file(file: impl AsRef<Path>) -> Result<SqlFile> {
+    let file = file.as_ref();
+    let path = String::from(file.to_string_lossy());
+    let content = read_file(file).await?;
+    let (dir, mut stack) = include_context(file).await?;
+    parse_sql_text(&content, &path, dir, &mut stack).await
+}
+
+/// 按MySQL客户端的规则把sql文件的内容拆分为多条sql，并展开其中
+/// 包含的文件。被包含的文件中的sql记录所在的文件及行
+///
+/// # Arguments
+///
+/// * `content` - 文件的内容
+/// * `path` - 文件的路径，用于错误信息
+/// * `dir` - 相对路径基于的目录，一般为内容所在文件的目录
+/// * `stack` - 正在展开的文件，用于检查循环包含
+pub async fn parse_sql_text(
+    content: &str,
+    path: &str,
+    dir: PathBuf,
+    stack: &mut Vec<PathBuf>,
+) -> Result<SqlFile> {
+    let tokens = split_with_includes(content.to_owned(), path.to_owned(), dir, stack).await?;
+    build_sql_file(tokens, path)
+}
+
+/// 按MySQL客户端的规则把sql文件的内容拆分为多条sql，不展开包含的
+/// 文件，内容中有包含的文件时报错
+///
+/// 支持单双引号及反引号，`--`、`#`及`/* */`注释，及`DELIMITER`命令。
+/// sql之间的`-- @block begin`与`-- @block end`之间的sql组成一个块，
+/// 其它指令作用于它后面的第一条sql
+///
+/// # Arguments
+///
+/// * `content` - 文件的内容
+/// * `path` - 文件的路径，用于错误信息
+pub fn parse_sql_file(content: &str, path: &str) -> Result<SqlFile> {
+    build_sql_file(split(content, path)?, path)
+}
+
+/// 拆分sql文件的内容，错误信息带上文件的路径
+fn split(content: &str, path: &str) -> Result<Vec<Token>> {
+    Splitter::new(content)
+        .split()
+        .map_err(|e| Error::msg(format!("{} of file[{}]", e, path)))
+}
+
+/// 拆分sql文件的内容，并把其中包含的文件替换为该文件拆分后的单元
+fn split_with_includes<'a>(
+    content: String,
+    path: String,
+    dir: PathBuf,
+    stack: &'a mut Vec<PathBuf>,
+) -> BoxFuture<'a, Result<Vec<Token>>> {
+    async move {
+        let mut tokens = vec![];
+        for token in split(&content, &path)? {
+            let (include, row) = match token {
+                Token::Include { path, row, .. } => (path, row),
+                token => {
+                    tokens.push(token);
+                    continue;
+                }
+            };
+            let canonical = resolve_include(&dir.join(include), row, stack).await?;
+            let included = read_file(&canonical).await?;
+            let included_path = String::from(canonical.to_string_lossy());
+            let included_dir = canonical
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            stack.push(canonical);
+            let included_tokens =
+                split_with_includes(included, included_path.clone(), included_dir, stack).await?;
+            stack.pop();
+            for mut token in included_tokens {
+                token.set_file(&included_path);
+                tokens.push(token);
+            }
+        }
+        Ok(tokens)
+    }
+    .boxed()
+}
+
+/// 把拆分后的单元组成sql及块，并把指令应用到sql上
+fn build_sql_file(tokens: Vec<Token>, path: &str) -> Result<SqlFile> {
+    let mut sqls = vec![];
+    let mut blocks = vec![];
+    // 当前块的开始位置，不在块中时为None
+    let mut block_begin = None;
+    // 等待应用到下一条sql的指令
+    let mut pending: Vec<(String, usize, String)> = vec![];
+    for token in tokens {
+        let (directive, row, file) = match token {
+            Token::Sql(mut sql) => {
+                for (directive, row, file) in pending.drain(..) {
+                    apply_directive(&mut sql, &directive).map_err(|e| {
+                        Error::msg(format!("{} at line {} of file[{}]", e, row + 1, file))
+                    })?;
+                }
+                sqls.push(sql);
+                if block_begin.is_none() {
+                    blocks.push(sqls.len() - 1..sqls.len());
+                }
+                continue;
+            }
+            Token::Directive { text, row, file } => (text, row, file),
+            Token::Include {
+                path: include, row, ..
+            } => {
+                return Err(Error::msg(format!(
+                    "Can not include file[{}] at line {} of file[{}]",
+                    include,
+                    row + 1,
+                    path
+                )))
+            }
+        };
+        // 错误信息中的文件，指令在被包含的文件中时为该文件
+        let file = file.as_deref().unwrap_or(path);
+        let words: Vec<String> = directive
+            .split_whitespace()
+            .map(str::to_lowercase)
+            .collect();
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        match words.as_slice() {
+            ["block", "begin"] => {
+                if block_begin.is_some() {
+                    return Err(Error::msg(format!(
+                        "Nested block at line {} of file[{}]",
+                        row + 1,
+                        file
+                    )));
+                }
+                block_begin = Some(sqls.len());
+            }
+            ["block", "end"] => match block_begin.take() {
+                // 空的块没有sql，直接忽略
+                Some(begin) if begin < sqls.len() => blocks.push(begin..sqls.len()),
+                Some(_) => {}
+                None => {
+                    return Err(Error::msg(format!(
+                        "Block end without begin at line {} of file[{}]",
+                        row + 1,
+                        file
+                    )))
+                }
+            },
+            _ => pending.push((directive, row, file.to_owned())),
+        }
+    }
+    if block_begin.is_some() {
+        return Err(Error::msg(format!("Unclosed block in file[{}]", path)));
+    }
+    if let Some((directive, row, file)) = pending.first() {
+        return Err(Error::msg(format!(
+            "No sql after directive `@{}` at line {} of file[{}]",
+            directive,
+            row + 1,
+            file
+        )));
+    }
+    Ok(SqlFile { sqls, blocks })
+}
+
+/// 把文本中`${name}`格式的占位符替换为模板变量的值
+///
+/// # Arguments
+///
+/// * `text` - 包含占位符的文本
+/// * `values` - 每个模板变量的值
+pub fn substitute(text: &str, values: &BTreeMap<String, String>) -> Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(begin) = rest.find("${") {
+        result.push_str(&rest[..begin]);
+        let end = rest[begin..]
+            .find('}')
+            .ok_or_else(|| Error::msg("Unclosed placeholder `${`"))?;
+        let name = rest[begin + 2..begin + end].trim();
+        let value = values
+            .get(name)
+            .ok_or_else(|| Error::msg(format!("Undefined template variable `{}`", name)))?;
+        result.push_str(value);
+        rest = &rest[begin + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// 找出从指定行开始的sql
+///
+/// # Arguments
+///
+/// * `sqls_list` - 每个文件的sql
+/// * `position` - (文件索引, 行)，都从0开始
+pub fn sql_at_row(
+    sqls_list: &[Vec<Sql>],
+    (file_idx, row): (usize, usize),
+) -> Result<(usize, usize)> {
+    sqls_list
+        .get(file_idx)
+        .and_then(|sqls| {
+            sqls.iter()
+                .position(|sql| sql.file.is_none() && sql.row == row)
+        })
+        .map(|sql_idx| (file_idx, sql_idx))
+        .ok_or_else(|| {
+            Error::msg(format!(
+                "There is no sql starting at file {} row {}",
+                file_idx + 1,
+                row + 1
+            ))
+        })
+}
+
+/// 把以块为单位的交错排列展开为每条sql的(文件索引, 文件中的位置)
+///
+/// # Arguments
+///
+/// * `blocks_list` - 每个文件的块
+/// * `steps` - 每个块的(文件索引, 块索引)
+pub fn expand_blocks(
+    blocks_list: &[Vec<Range<usize>>],
+    steps: &[(usize, usize)],
+) -> Vec<(usize, usize)> {
+    steps
+        .iter()
+        .flat_map(|&(file_idx, block_idx)| {
+            blocks_list[file_idx][block_idx]
+                .clone()
+                .map(move |sql_idx| (file_idx, sql_idx))
+        })
+        .collect()
+}
+
+/// 读取文件的内容，`-`表示从标准输入读取，标准输入只能读取一次
+pub async fn read_file(file: impl AsRef<Path>) -> Result<String> {
     let mut result = vec![];
-    let mut path = None;
-    if log_enabled!(Debug) {
-        path = Some(String::from(file.as_ref().to_string_lossy()));
+    if file.as_ref() == Path::new(STDIN) {
+        if STDIN_READ.swap(true, Ordering::SeqCst) {
+            return Err(Error::msg("Standard input can only be read once"));
+        }
+        tokio::io::stdin().read_to_end(&mut result).await?;
+    } else {
+        File::open(file).await?.read_to_end(&mut result).await?;
     }
-    File::open(file).await?.read_to_end(&mut result).await?;
+    Ok(String::from_utf8(result)?)
+}
+
+/// 如果是`source file`、`\. file`或`-- @include file`，返回其中的文件路径
+fn include_path(line: &str) -> Option<&str> {
+    let line = line.trim();
+    let rest = match line.strip_prefix("--") {
+        Some(comment) if comment.starts_with(char::is_whitespace) => {
+            comment.trim_start().strip_prefix("@include")?
+        }
+        Some(_) => return None,
+        None => {
+            let command_len = line.find(char::is_whitespace)?;
+            let command = &line[..command_len];
+            if !command.eq_ignore_ascii_case("source") && command != "\\." {
+                return None;
+            }
+            line[command_len..].trim_end_matches(';')
+        }
+    };
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let path = rest.trim();
+    if path.is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+/// 检查被包含的文件是否存在，及是否循环包含，返回文件的规范路径
+///
+/// # Arguments
+///
+/// * `path` - 被包含的文件
+/// * `row` - 包含指令所在的行，用于错误信息
+/// * `stack` - 正在展开的文件，最后一个为包含指令所在的文件
+async fn resolve_include(path: &Path, row: usize, stack: &[PathBuf]) -> Result<PathBuf> {
+    let canonical = fs::canonicalize(path).await.map_err(|e| {
+        let including = match stack.last() {
+            Some(file) => format!(" of file[{}]", file.to_string_lossy()),
+            None => String::new(),
+        };
+        Error::msg(format!(
+            "Can not include file[{}] at line {}{}: {}",
+            path.to_string_lossy(),
+            row + 1,
+            including,
+            e
+        ))
+    })?;
+    if stack.contains(&canonical) {
+        let files: Vec<String> = stack
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|file| file.to_string_lossy().into_owned())
+            .collect();
+        return Err(Error::msg(format!("Include cycle: {}", files.join(" -> "))));
+    }
+    Ok(canonical)
+}
+
+/// 展开内容中sql之间的`source`命令及`-- @include`指令，被包含的
+/// 文件的内容会代替指令所在的行，文件中也可以包含其它文件。sql中间
+/// 的同样内容不是包含指令，原样保留
+///
+/// # Arguments
+///
+/// * `content` - 要展开的内容
+/// * `dir` - 相对路径基于的目录，一般为内容所在文件的目录
+/// * `stack` - 正在展开的文件，用于检查循环包含
+pub fn expand_includes<'a>(
+    content: String,
+    dir: PathBuf,
+    stack: &'a mut Vec<PathBuf>,
+) -> BoxFuture<'a, Result<String>> {
+    async move {
+        let path = match stack.last() {
+            Some(file) => String::from(file.to_string_lossy()),
+            None => String::from(STDIN),
+        };
+        let chars: Vec<char> = content.chars().collect();
+        let mut result = String::with_capacity(content.len());
+        // 下一个要复制的字符
+        let mut pos = 0;
+        for token in split(&content, &path)? {
+            let (include, row, span) = match token {
+                Token::Include { path, row, span } => (path, row, span),
+                _ => continue,
+            };
+            result.extend(&chars[pos..span.start]);
+            pos = span.end;
+            let canonical = resolve_include(&dir.join(include), row, stack).await?;
+            let included = read_file(&canonical).await?;
+            let included_dir = canonical
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            stack.push(canonical);
+            let included = expand_includes(included, included_dir, stack).await?;
+            stack.pop();
+            result.push_str(&included);
+        }
+        result.extend(&chars[pos..]);
+        Ok(result)
+    }
+    .boxed()
+}
+
+/// 包含的文件的相对路径基于的目录，及正在展开的文件。标准输入中
+/// 包含的文件相对于当前目录
+async fn include_context(file: &Path) -> Result<(PathBuf, Vec<PathBuf>)> {
+    if file == Path::new(STDIN) {
+        return Ok((PathBuf::new(), vec![]));
+    }
+    let canonical = fs::canonicalize(file).await?;
+    let dir = canonical
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    Ok((dir, vec![canonical]))
+}
+
+/// 读取sql文件的内容，并展开其中包含的文件，`-`表示从标准输入读取
+pub async fn read_sqls(file: impl AsRef<Path>) -> Result<String> {
+    let file = file.as_ref();
+    let content = read_file(file).await?;
+    let (dir, mut stack) = include_context(file).await?;
+    let result = expand_includes(content, dir, &mut stack).await?;
     if log_enabled!(Debug) {
-        log::debug!(
-            "read from file[{:?}]:\n{}",
-            path,
-            String::from_utf8_lossy(&result)
+        log::debug!("read from file[{}]:\n{}", file.to_string_lossy(), result);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 拆分后每条sql的文本及开始的行（从0开始）
+    fn split_sqls(content: &str) -> Vec<(String, usize)> {
+        parse_sql_file(content, "test.sql")
+            .unwrap()
+            .sqls
+            .into_iter()
+            .map(|sql| (sql.text, sql.row))
+            .collect()
+    }
+
+    #[test]
+    fn test_split_statements() {
+        let content = "select 1;\n\nselect\n  2;\nselect 3";
+        assert_eq!(
+            split_sqls(content),
+            vec![
+                (String::from("select 1"), 0),
+                (String::from("select\n  2"), 2),
+                (String::from("select 3"), 4),
+            ]
         );
     }
-    Ok(String::from_utf8(result)?)
+
+    #[test]
+    fn test_split_without_delimiter() {
+        // 没有分号时，所有行是同一条sql
+        let content = "select 1\nselect 2\n";
+        assert_eq!(
+            split_sqls(content),
+            vec![(String::from("select 1\nselect 2"), 0)]
+        );
+    }
+
+    #[test]
+    fn test_split_quotes_and_comments() {
+        let content = "-- comment;\n# comment;\n/* comment; */\n\
+                       select 'a;''b', \"c\\\";\", `d;`;\n\
+                       /*!40101 set names utf8 */;";
+        assert_eq!(
+            split_sqls(content),
+            vec![
+                (String::from("select 'a;''b', \"c\\\";\", `d;`"), 3),
+                (String::from("/*!40101 set names utf8 */"), 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_delimiter_command() {
+        let content = "DELIMITER //\ncreate procedure p() begin select 1; end//\n\
+                       delimiter ;\nselect 2;";
+        assert_eq!(
+            split_sqls(content),
+            vec![
+                (String::from("create procedure p() begin select 1; end"), 1),
+                (String::from("select 2"), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_directives_and_blocks() {
+        let content = "-- @label first\n-- @expect-error 1062\n-- @sleep 50ms\n\
+                       insert into t values(1);\n-- @block begin\nselect 1;\nselect 2;\n\
+                       -- @block end\nselect 3;";
+        let sql_file = parse_sql_file(content, "test.sql").unwrap();
+        let first = &sql_file.sqls[0];
+        assert_eq!(first.label.as_deref(), Some("first"));
+        assert_eq!(first.expect_error, Some(1062));
+        assert_eq!(first.sleep, Some(Duration::from_millis(50)));
+        assert_eq!(first.row, 3);
+        assert_eq!(sql_file.blocks, vec![0..1, 1..3, 3..4]);
+    }
+
+    #[test]
+    fn test_split_errors() {
+        assert!(parse_sql_file("select 'a;", "test.sql").is_err());
+        assert!(parse_sql_file("select 1 /* a;", "test.sql").is_err());
+        assert!(parse_sql_file("select 1\n-- @label a\n;", "test.sql").is_err());
+        assert!(parse_sql_file("-- @block begin\nselect 1;", "test.sql").is_err());
+        assert!(parse_sql_file("-- @label a\n", "test.sql").is_err());
+    }
+
+    /// 在临时目录中创建测试用的文件，返回目录
+    fn create_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sql-permutation-{}-{}", name, std::process::id()));
+        for (file, content) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_include_relative_path() {
+        let dir = create_files(
+            "include",
+            &[
+                ("main.sql", "select 1;\n-- @include sub/a.sql\nselect 4;\n"),
+                // 相对于被包含的文件所在的目录
+                ("sub/a.sql", "select 2;\nsource b.sql;\n"),
+                ("sub/b.sql", "\n-- @label three\nselect 3;\n"),
+            ],
+        );
+        let sql_file = read_sql_file(dir.join("main.sql")).await.unwrap();
+        let texts: Vec<&str> = sql_file.sqls.iter().map(|sql| sql.text.as_str()).collect();
+        assert_eq!(texts, ["select 1", "select 2", "select 3", "select 4"]);
+        let rows: Vec<usize> = sql_file.sqls.iter().map(|sql| sql.row).collect();
+        assert_eq!(rows, [0, 0, 2, 2]);
+        let files: Vec<Option<&str>> = sql_file
+            .sqls
+            .iter()
+            .map(|sql| sql.file.as_deref().and_then(|file| file.rsplit('/').next()))
+            .collect();
+        assert_eq!(files, [None, Some("a.sql"), Some("b.sql"), None]);
+        assert_eq!(sql_file.sqls[2].label.as_deref(), Some("three"));
+        // 文件路径不规范时同样相对于文件所在的目录，指令所在的行换成
+        // 文件的内容，保留指令后的换行
+        let sqls = read_sqls(dir.join("sub/../main.sql")).await.unwrap();
+        assert_eq!(
+            sqls,
+            "select 1;\nselect 2;\n\n-- @label three\nselect 3;\n\n\nselect 4;\n"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_include_cycle() {
+        let dir = create_files(
+            "include-cycle",
+            &[
+                ("self.sql", "select 1;\nsource self.sql\n"),
+                ("a.sql", "select 1;\n-- @include sub/b.sql\n"),
+                ("sub/b.sql", "select 2;\n\\. ../a.sql\n"),
+                ("missing.sql", "-- @include none.sql\n"),
+                // 同一个文件被包含多次不是循环
+                ("twice.sql", "source sub/c.sql\nsource sub/c.sql\n"),
+                ("sub/c.sql", "select 3;\n"),
+            ],
+        );
+        for file in ["self.sql", "a.sql", "sub/b.sql"].iter() {
+            let err = read_sql_file(dir.join(file)).await.err().unwrap();
+            assert!(err.to_string().starts_with("Include cycle: "), "{}", err);
+            let err = read_sqls(dir.join(file)).await.err().unwrap();
+            assert!(err.to_string().starts_with("Include cycle: "), "{}", err);
+        }
+        let err = read_sql_file(dir.join("a.sql"))
+            .await
+            .err()
+            .unwrap()
+            .to_string();
+        let cycle: Vec<&str> = err
+            .split(" -> ")
+            .map(|file| file.rsplit('/').next().unwrap())
+            .collect();
+        assert_eq!(cycle, ["a.sql", "b.sql", "a.sql"]);
+        let err = read_sql_file(dir.join("missing.sql")).await.err().unwrap();
+        assert!(
+            err.to_string().starts_with("Can not include file["),
+            "{}",
+            err
+        );
+        let sql_file = read_sql_file(dir.join("twice.sql")).await.unwrap();
+        assert_eq!(sql_file.sqls.len(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,344 @@
+use anyhow::{Error, Result};
+
+/// MySQL general log中的所有命令，不是这些命令开头的行是上一条
+/// sql的后续行
+const MYSQL_COMMANDS: &[&str] = &[
+    "Sleep",
+    "Quit",
+    "Init DB",
+    "Query",
+    "Field List",
+    "Create DB",
+    "Drop DB",
+    "Refresh",
+    "Shutdown",
+    "Statistics",
+    "Processlist",
+    "Connect",
+    "Kill",
+    "Debug",
+    "Ping",
+    "Time",
+    "Delayed insert",
+    "Change user",
+    "Binlog Dump",
+    "Table Dump",
+    "Connect Out",
+    "Register Slave",
+    "Prepare",
+    "Execute",
+    "Long Data",
+    "Close stmt",
+    "Reset stmt",
+    "Set option",
+    "Fetch",
+    "Daemon",
+    "Binlog Dump GTID",
+    "Reset Connection",
+];
+
+/// 一个客户端连接执行的sql
+pub struct Connection {
+    pub id: u64,
+    pub sqls: Vec<String>,
+}
+
+/// 按连接分组的sql，保持连接第一次出现的顺序
+#[derive(Default)]
+struct Connections {
+    connections: Vec<Connection>,
+}
+
+impl Connections {
+    fn get_mut(&mut self, id: u64) -> &mut Connection {
+        let idx = match self.connections.iter().position(|conn| conn.id == id) {
+            Some(idx) => idx,
+            None => {
+                self.connections.push(Connection { id, sqls: vec![] });
+                self.connections.len() - 1
+            }
+        };
+        &mut self.connections[idx]
+    }
+}
+
+/// 去掉MySQL general log行首的时间，支持5.7以后的`2020-08-30T10:18:21.825655Z`
+/// 及5.6的`200830 10:18:21`，没有时间时原样返回
+fn strip_mysql_time(line: &str) -> &str {
+    let first = line.split_whitespace().next().unwrap_or("");
+    if !first.starts_with(|c: char| c.is_ascii_digit()) {
+        return line;
+    }
+    let rest = line.trim_start()[first.len()..].trim_start();
+    if first.contains('T') {
+        return rest;
+    }
+    // 5.6的时间包括日期和时间两部分
+    let second = rest.split_whitespace().next().unwrap_or("");
+    if first.len() == 6 && second.contains(':') {
+        return rest[second.len()..].trim_start();
+    }
+    line
+}
+
+/// 是否MySQL general log开头的说明，MySQL启动或者`FLUSH LOGS`时会
+/// 重新输出：
+///
+/// ```text
+/// /usr/sbin/mysqld, Version: 5.7.30-log (MySQL Community Server (GPL)). started with:
+/// Tcp port: 3306  Unix socket: /var/run/mysqld/mysqld.sock
+/// Time                 Id Command    Argument
+/// ```
+fn is_mysql_header(line: &str) -> bool {
+    let line = line.trim_end();
+    (line.contains(", Version: ") && line.ends_with("started with:"))
+        || line.starts_with("Tcp port: ")
+        || line
+            .split_whitespace()
+            .eq(["Time", "Id", "Command", "Argument"].iter().copied())
+}
+
+/// 解析MySQL general log的一行，返回(连接id, 命令, 参数)，不是新
+/// 命令的行返回None
+fn parse_mysql_line(line: &str) -> Option<(u64, &str, &str)> {
+    let rest = strip_mysql_time(line).trim_start();
+    let id_len = rest.find(|c: char| !c.is_ascii_digit())?;
+    if id_len == 0 {
+        return None;
+    }
+    let id = rest[..id_len].parse().ok()?;
+    let rest = rest[id_len..].trim_start_matches(' ');
+    let (command, argument) = match rest.find('\t') {
+        Some(tab) => (&rest[..tab], &rest[tab + 1..]),
+        None => (rest.trim_end(), ""),
+    };
+    if !MYSQL_COMMANDS.contains(&command) {
+        return None;
+    }
+    Some((id, command, argument))
+}
+
+/// 取出TiDB日志中`[name=value]`字段的值，带引号的值会去掉引号并
+/// 处理转义
+fn tidb_field(line: &str, name: &str) -> Option<String> {
+    let begin = line.find(&format!("[{}=", name))? + name.len() + 2;
+    let rest = &line[begin..];
+    if !rest.starts_with('"') {
+        return rest.find(']').map(|end| rest[..end].to_owned());
+    }
+    let mut value = String::new();
+    let mut chars = rest[1..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                'u' => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    value.push(char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+                }
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// 解析MySQL或TiDB的general log，按连接分组其中执行的sql
+///
+/// MySQL的日志中，`Query`及`Execute`命令的参数作为sql，`Init DB`
+/// 转换为`USE`语句，其它命令被忽略；TiDB的日志中，只处理包含
+/// `[GENERAL_LOG]`的行，取出其中的`conn`及`sql`字段
+pub fn parse_general_log(content: &str) -> Result<Vec<Connection>> {
+    let mut connections = Connections::default();
+    // 上一条MySQL命令是否sql，用于拼接多行的sql
+    let mut last: Option<u64> = None;
+    for (row, line) in content.lines().enumerate() {
+        if line.contains("[GENERAL_LOG]") {
+            last = None;
+            let invalid = || {
+                Error::msg(format!(
+                    "Invalid TiDB general log at line {}: {}",
+                    row + 1,
+                    line
+                ))
+            };
+            let id = tidb_field(line, "conn")
+                .and_then(|id| id.parse().ok())
+                .ok_or_else(invalid)?;
+            let sql = tidb_field(line, "sql").ok_or_else(invalid)?;
+            connections.get_mut(id).sqls.push(sql);
+            continue;
+        }
+        if is_mysql_header(line) {
+            last = None;
+            continue;
+        }
+        match parse_mysql_line(line) {
+            Some((id, command, argument)) => {
+                last = None;
+                let sql = match command {
+                    "Query" | "Execute" => argument.to_owned(),
+                    "Init DB" => format!("USE `{}`", argument.trim()),
+                    _ => continue,
+                };
+                connections.get_mut(id).sqls.push(sql);
+                if command != "Init DB" {
+                    last = Some(id);
+                }
+            }
+            None => {
+                // 多行的sql，拼接到上一条sql
+                if let Some(id) = last {
+                    let sql = connections.get_mut(id).sqls.last_mut().unwrap();
+                    sql.push('\n');
+                    sql.push_str(line);
+                }
+            }
+        }
+    }
+    Ok(connections.connections)
+}
+
+/// 把一个连接的sql输出为`file::read_sql_file`可以读取的sql文件，
+/// 包含分号的sql使用`DELIMITER`修改分隔符
+pub fn format_session_file(sqls: &[String]) -> String {
+    let mut content = String::new();
+    for sql in sqls {
+        let sql = sql.trim().trim_end_matches(';').trim_end();
+        if sql.is_empty() {
+            continue;
+        }
+        if !sql.contains(';') {
+            content.push_str(sql);
+            content.push_str(";\n");
+            continue;
+        }
+        let delimiter = ["//", "$$", ";;", "@@"]
+            .iter()
+            .find(|delimiter| !sql.contains(*delimiter))
+            .unwrap_or(&"//");
+        content.push_str(&format!(
+            "DELIMITER {}\n{}{}\nDELIMITER ;\n",
+            delimiter, sql, delimiter
+        ));
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sqls(content: &str) -> Vec<(u64, Vec<String>)> {
+        parse_general_log(content)
+            .unwrap()
+            .into_iter()
+            .map(|conn| (conn.id, conn.sqls))
+            .collect()
+    }
+
+    #[test]
+    fn test_mysql_57() {
+        let content = "2020-08-30T10:18:21.825655Z\t    5 Connect\troot@localhost on test\n\
+                       2020-08-30T10:18:21.826000Z\t    5 Query\tselect 1\n\
+                       2020-08-30T10:18:21.827000Z\t    6 Init DB\ttest\n\
+                       2020-08-30T10:18:21.828000Z\t    6 Query\tselect *\n\
+                       from t\n\
+                       where id = 1\n\
+                       2020-08-30T10:18:21.829000Z\t    5 Quit\t\n";
+        assert_eq!(
+            sqls(content),
+            vec![
+                (5, vec![String::from("select 1")]),
+                (
+                    6,
+                    vec![
+                        String::from("USE `test`"),
+                        String::from("select *\nfrom t\nwhere id = 1"),
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mysql_56() {
+        let content = "200830 10:18:21\t    5 Query\tselect 1\n\
+                       \t\t    5 Query\tselect 2\n\
+                       200830 10:18:22\t    7 Execute\tselect 3\n";
+        assert_eq!(
+            sqls(content),
+            vec![
+                (5, vec![String::from("select 1"), String::from("select 2")]),
+                (7, vec![String::from("select 3")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mysql_header_skipped() {
+        let content = "2020-08-30T10:18:21.826000Z\t    5 Query\tselect 1\n\
+                       /usr/sbin/mysqld, Version: 5.7.30-log (MySQL Community Server (GPL)). started with:\n\
+                       Tcp port: 3306  Unix socket: /var/run/mysqld/mysqld.sock\n\
+                       Time                 Id Command    Argument\n\
+                       2020-08-30T10:18:22.826000Z\t    5 Query\tselect 2\n";
+        assert_eq!(
+            sqls(content),
+            vec![(5, vec![String::from("select 1"), String::from("select 2")])]
+        );
+    }
+
+    #[test]
+    fn test_tidb() {
+        let content = "[2020/08/30 10:18:21.825 +08:00] [INFO] [session.go:2259] [GENERAL_LOG] \
+                       [conn=3] [user=root@127.0.0.1] [schemaVersion=22] [txnStartTS=0] \
+                       [forUpdateTS=0] [isReadConsistency=false] [current_db=test] [txn_mode=PESSIMISTIC] \
+                       [sql=\"select \\\"a]\\\"\\nfrom t\"]\n\
+                       [2020/08/30 10:18:21.826 +08:00] [INFO] [session.go:2259] [GENERAL_LOG] \
+                       [conn=3] [user=root@127.0.0.1] [sql=select\\u00201]\n\
+                       [2020/08/30 10:18:21.827 +08:00] [INFO] [server.go:388] [\"new connection\"]\n";
+        assert_eq!(
+            sqls(content),
+            vec![(
+                3,
+                vec![
+                    String::from("select \"a]\"\nfrom t"),
+                    String::from("select\\u00201")
+                ]
+            )]
+        );
+        assert!(parse_general_log("[GENERAL_LOG] [sql=select 1]").is_err());
+    }
+
+    #[test]
+    fn test_format_session_file() {
+        let sqls = vec![
+            String::from("select 1;"),
+            String::from("  "),
+            String::from("create procedure p() begin select 1; end"),
+            String::from("select '//'; select 2"),
+        ];
+        assert_eq!(
+            format_session_file(&sqls),
+            "select 1;\n\
+             DELIMITER //\ncreate procedure p() begin select 1; end//\nDELIMITER ;\n\
+             DELIMITER $$\nselect '//'; select 2$$\nDELIMITER ;\n"
+        );
+        // 输出的文件可以拆分回原来的sql
+        let sql_file =
+            crate::file::parse_sql_file(&format_session_file(&sqls), "session.sql").unwrap();
+        let texts: Vec<&str> = sql_file.sqls.iter().map(|sql| sql.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "select 1",
+                "create procedure p() begin select 1; end",
+                "select '//'; select 2"
+            ]
+        );
+    }
+}
@@ -3,8 +3,15 @@ use std::path::Path;
 
 pub mod algo;
 pub mod arg;
+pub mod checkpoint;
+pub mod commute;
 pub mod concurrent;
+pub mod constraint;
 pub mod file;
+pub mod import;
+pub mod scenario;
+pub mod session;
+pub mod symmetry;
 
 pub fn init_log(log_config_file: Option<impl AsRef<Path>>) -> Result<()> {
     match log_config_file {
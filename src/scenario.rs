@@ -0,0 +1,560 @@
+use anyhow::{Error, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::Path;
+use toml::Value;
+
+use crate::constraint::HappensBefore;
+use crate::file::{self, Sql};
+
+/// 场景文件中的一个会话，sql可以写在`sql`中，也可以用`file`指定
+/// 相对于场景文件的sql文件
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SessionConfig {
+    name: Option<String>,
+    file: Option<String>,
+    sql: Option<String>,
+    /// 每个批次开始前在会话上设置的会话变量
+    #[serde(default)]
+    variables: BTreeMap<String, Value>,
+}
+
+/// 对一条sql的期望，与sql文件中的`-- @expect-error`及
+/// `-- @expect-rows`指令相同
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExpectationConfig {
+    /// `会话:行`或`会话:标签`
+    at: String,
+    error: Option<u16>,
+    rows: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioConfig {
+    name: Option<String>,
+    init: Option<String>,
+    init_file: Option<String>,
+    reset: Option<String>,
+    reset_file: Option<String>,
+    /// 每个批次后检查最终状态的sql，可以不指定
+    check: Option<String>,
+    check_file: Option<String>,
+    #[serde(default)]
+    clusters: Vec<String>,
+    #[serde(default)]
+    session_per_file: bool,
+    sessions: Vec<SessionConfig>,
+    /// `会话:行<会话:行`格式的先后约束，也可以使用标签
+    #[serde(default)]
+    happens_before: Vec<String>,
+    #[serde(default)]
+    expectations: Vec<ExpectationConfig>,
+    /// 每个模板变量的所有取值
+    #[serde(default)]
+    matrix: BTreeMap<String, Vec<Value>>,
+}
+
+/// 一个完整的测试场景，包括初始化及重置的sql，交错执行的每个会话
+/// 的sql，会话变量，及先后约束
+#[derive(Clone)]
+pub struct Scenario {
+    pub name: String,
+    pub init_sqls: String,
+    pub reset_sqls: String,
+    /// 每个批次后检查最终状态的sql，为空时不检查
+    pub check_sqls: Vec<Sql>,
+    /// 数据库的url，可以被命令行参数覆盖
+    pub clusters: Vec<String>,
+    pub session_per_file: bool,
+    /// 每个会话的名字，对应一个sql文件
+    pub session_names: Vec<String>,
+    pub sqls_list: Vec<Vec<Sql>>,
+    pub blocks_list: Vec<Vec<Range<usize>>>,
+    /// 每个会话在批次开始前执行的`SET SESSION`语句
+    pub session_variables: Vec<Option<String>>,
+    /// sql位置已经换算成(文件索引, 文件中的位置)的先后约束
+    pub constraints: Vec<HappensBefore>,
+    /// 每个模板变量的所有取值，sql中的`${name}`会被替换为变量的值，
+    /// 每种取值的组合单独执行
+    pub matrix: BTreeMap<String, Vec<String>>,
+}
+
+impl Scenario {
+    /// 由命令行指定的sql文件组成的场景，会话以文件的序号命名
+    ///
+    /// # Arguments
+    ///
+    /// * `init_file` - 初始化sql文件
+    /// * `reset_file` - 重置sql文件
+    /// * `sql_files` - 交错执行的sql文件
+    pub async fn from_files(init_file: &str, reset_file: &str, sql_files: &[&str]) -> Result<Self> {
+        let mut scenario = Scenario {
+            name: String::new(),
+            init_sqls: file::read_sqls(init_file).await?,
+            reset_sqls: file::read_sqls(reset_file).await?,
+            check_sqls: vec![],
+            clusters: vec![],
+            session_per_file: false,
+            session_names: vec![],
+            sqls_list: vec![],
+            blocks_list: vec![],
+            session_variables: vec![],
+            constraints: vec![],
+            matrix: BTreeMap::new(),
+        };
+        for (file_idx, sql_file) in sql_files.iter().enumerate() {
+            let sql_file = file::read_sql_file(sql_file).await?;
+            scenario.session_names.push((file_idx + 1).to_string());
+            scenario.sqls_list.push(sql_file.sqls);
+            scenario.blocks_list.push(sql_file.blocks);
+            scenario.session_variables.push(None);
+        }
+        Ok(scenario)
+    }
+
+    /// 读取TOML格式的场景文件，场景中的相对路径相对于场景文件所在
+    /// 的目录，没有指定名字时以文件名作为场景的名字
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let display = path.to_string_lossy().into_owned();
+        let content = file::read_file(path).await?;
+        let config: ScenarioConfig = toml::from_str(&content)
+            .map_err(|e| Error::msg(format!("Invalid scenario file[{}]: {}", display, e)))?;
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let name = config.name.clone().unwrap_or_else(|| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        });
+        let mut scenario = Scenario {
+            name,
+            init_sqls: read_text(&dir, config.init, config.init_file, "init").await?,
+            reset_sqls: read_text(&dir, config.reset, config.reset_file, "reset").await?,
+            check_sqls: vec![],
+            clusters: config.clusters,
+            session_per_file: config.session_per_file,
+            session_names: vec![],
+            sqls_list: vec![],
+            blocks_list: vec![],
+            session_variables: vec![],
+            constraints: vec![],
+            matrix: BTreeMap::new(),
+        };
+        if config.check.is_some() || config.check_file.is_some() {
+            let check = read_text(&dir, config.check, config.check_file, "check").await?;
+            scenario.check_sqls = file::parse_sql_file(&check, &format!("{}#check", display))?.sqls;
+        }
+        for (name, values) in config.matrix {
+            if values.is_empty() {
+                return Err(Error::msg(format!(
+                    "Template variable `{}` has no values in scenario file[{}]",
+                    name, display
+                )));
+            }
+            let values = values
+                .into_iter()
+                .map(|value| match value {
+                    Value::String(s) => Ok(s),
+                    Value::Integer(_) | Value::Float(_) | Value::Boolean(_) => {
+                        Ok(value.to_string())
+                    }
+                    _ => Err(Error::msg(format!(
+                        "Values of template variable `{}` must be strings, numbers or booleans",
+                        name
+                    ))),
+                })
+                .collect::<Result<_>>()?;
+            scenario.matrix.insert(name, values);
+        }
+        if config.sessions.is_empty() {
+            return Err(Error::msg(format!(
+                "No session in scenario file[{}]",
+                display
+            )));
+        }
+        for (session_idx, session) in config.sessions.into_iter().enumerate() {
+            let name = session
+                .name
+                .unwrap_or_else(|| (session_idx + 1).to_string());
+            if scenario.session_names.contains(&name) {
+                return Err(Error::msg(format!(
+                    "Duplicate session `{}` in scenario file[{}]",
+                    name, display
+                )));
+            }
+            let sql_file = match (session.sql, session.file) {
+                (Some(sql), None) => {
+                    let path = format!("{}#{}", display, name);
+                    file::parse_sql_text(&sql, &path, dir.clone(), &mut vec![]).await?
+                }
+                (None, Some(file)) => file::read_sql_file(dir.join(file)).await?,
+                _ => {
+                    return Err(Error::msg(format!(
+                        "Session `{}` must have exactly one of `sql` and `file`",
+                        name
+                    )))
+                }
+            };
+            scenario.session_names.push(name);
+            scenario.sqls_list.push(sql_file.sqls);
+            scenario.blocks_list.push(sql_file.blocks);
+            scenario
+                .session_variables
+                .push(set_session_sql(&session.variables)?);
+        }
+        for constraint in config.happens_before.iter() {
+            let constraint = scenario.parse_constraint(constraint)?;
+            scenario.constraints.push(constraint);
+        }
+        for expectation in config.expectations {
+            let (file_idx, sql_idx) = scenario.resolve(&expectation.at)?;
+            let sql = &mut scenario.sqls_list[file_idx][sql_idx];
+            if expectation.error.is_some() {
+                sql.expect_error = expectation.error;
+            }
+            if expectation.rows.is_some() {
+                sql.expect_rows = expectation.rows;
+            }
+        }
+        Ok(scenario)
+    }
+
+    /// 把`会话:行`或`会话:标签`换算成(文件索引, 文件中的位置)，会话
+    /// 可以是名字或者从1开始的序号，行是sql开始的行
+    pub fn resolve(&self, position: &str) -> Result<(usize, usize)> {
+        let invalid = || {
+            Error::msg(format!(
+                "sql position `{}` must be in format `session:row` or `session:label`!",
+                position
+            ))
+        };
+        let mut parts = position.trim().splitn(2, ':');
+        let session = parts.next().ok_or_else(invalid)?.trim();
+        let row = parts.next().ok_or_else(invalid)?.trim();
+        let file_idx = match self.session_names.iter().position(|name| name == session) {
+            Some(file_idx) => file_idx,
+            None => match session.parse::<usize>() {
+                Ok(file_idx) if file_idx >= 1 && file_idx <= self.session_names.len() => {
+                    file_idx - 1
+                }
+                _ => return Err(Error::msg(format!("There is no session `{}`", session))),
+            },
+        };
+        if let Ok(row) = row.parse::<usize>() {
+            if row == 0 {
+                return Err(invalid());
+            }
+            return file::sql_at_row(&self.sqls_list, (file_idx, row - 1));
+        }
+        self.sqls_list[file_idx]
+            .iter()
+            .position(|sql| sql.label.as_deref() == Some(row))
+            .map(|sql_idx| (file_idx, sql_idx))
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "There is no sql labeled `{}` in session `{}`",
+                    row, session
+                ))
+            })
+    }
+
+    /// 解析`会话:行<会话:行`格式的先后约束，位置的格式与`resolve`相同
+    pub fn parse_constraint(&self, constraint: &str) -> Result<HappensBefore> {
+        let invalid = || {
+            Error::msg(format!(
+                "constraint `{}` must be in format `session:row<session:row`!",
+                constraint
+            ))
+        };
+        let mut parts = constraint.splitn(2, '<');
+        let before = self.resolve(parts.next().ok_or_else(invalid)?)?;
+        let after = self.resolve(parts.next().ok_or_else(invalid)?)?;
+        Ok(HappensBefore { before, after })
+    }
+
+    /// 模板变量所有取值的组合，没有模板变量时只有一个空的组合
+    pub fn combinations(&self) -> Vec<BTreeMap<String, String>> {
+        let mut combinations = vec![BTreeMap::new()];
+        for (name, values) in self.matrix.iter() {
+            let mut next = Vec::with_capacity(combinations.len() * values.len());
+            for combination in combinations.iter() {
+                for value in values {
+                    let mut combination = combination.clone();
+                    combination.insert(name.clone(), value.clone());
+                    next.push(combination);
+                }
+            }
+            combinations = next;
+        }
+        combinations
+    }
+
+    /// 把sql中的占位符替换为一种取值组合，得到要执行的场景，场景的
+    /// 名字会带上取值组合。没有模板变量时直接返回场景的拷贝
+    pub fn instantiate(&self, values: &BTreeMap<String, String>) -> Result<Scenario> {
+        let mut scenario = self.clone();
+        if self.matrix.is_empty() {
+            return Ok(scenario);
+        }
+        scenario.matrix.clear();
+        let assignments: Vec<String> = values
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        scenario.name = format!("{} [{}]", self.name, assignments.join(", "))
+            .trim()
+            .to_owned();
+        scenario.init_sqls = file::substitute(&self.init_sqls, values)
+            .map_err(|e| Error::msg(format!("{} in init sql", e)))?;
+        scenario.reset_sqls = file::substitute(&self.reset_sqls, values)
+            .map_err(|e| Error::msg(format!("{} in reset sql", e)))?;
+        for sql in scenario.check_sqls.iter_mut() {
+            sql.text = file::substitute(&sql.text, values)
+                .map_err(|e| Error::msg(format!("{} in check sql", e)))?;
+        }
+        for (file_idx, sqls) in scenario.sqls_list.iter_mut().enumerate() {
+            for sql in sqls.iter_mut() {
+                sql.text = file::substitute(&sql.text, values)
+                    .map_err(|e| Error::msg(format!("{} at {}", e, sql.position(file_idx))))?;
+            }
+        }
+        for session_variables in scenario.session_variables.iter_mut().flatten() {
+            *session_variables = file::substitute(session_variables, values)?;
+        }
+        Ok(scenario)
+    }
+
+    /// 每个会话的块数量，交错排列以块为单位
+    pub fn sizes(&self) -> Vec<usize> {
+        self.blocks_list.iter().map(Vec::len).collect()
+    }
+}
+
+/// 读取直接写在场景文件中，或者由文件指定的sql，并展开其中包含的文件
+async fn read_text(
+    dir: &Path,
+    text: Option<String>,
+    file: Option<String>,
+    key: &str,
+) -> Result<String> {
+    match (text, file) {
+        (Some(text), None) => file::expand_includes(text, dir.to_path_buf(), &mut vec![]).await,
+        (None, Some(file)) => file::read_sqls(dir.join(file)).await,
+        _ => Err(Error::msg(format!(
+            "Scenario must have exactly one of `{}` and `{}_file`",
+            key, key
+        ))),
+    }
+}
+
+/// 把会话变量拼成`SET SESSION`语句，字符串会加上引号，布尔值为
+/// `ON`或`OFF`
+fn set_session_sql(variables: &BTreeMap<String, Value>) -> Result<Option<String>> {
+    if variables.is_empty() {
+        return Ok(None);
+    }
+    let mut assignments = vec![];
+    for (name, value) in variables {
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(Error::msg(format!("Invalid session variable `{}`", name)));
+        }
+        let value = match value {
+            Value::String(s) => format!("'{}'", s.replace('\\', "\\\\").replace('\'', "''")),
+            Value::Integer(i) => i.to_string(),
+            Value::Float(f) => f.to_string(),
+            Value::Boolean(true) => String::from("ON"),
+            Value::Boolean(false) => String::from("OFF"),
+            _ => {
+                return Err(Error::msg(format!(
+                    "Session variable `{}` must be a string, number or boolean",
+                    name
+                )))
+            }
+        };
+        assignments.push(format!("{} = {}", name, value));
+    }
+    Ok(Some(format!("SET SESSION {}", assignments.join(", "))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 会话A每行一条sql，会话B的第一条sql占两行，第三条sql有标签
+    fn scenario() -> Scenario {
+        let a = file::parse_sql_file("select 1;\nselect 2;\nselect 3;\n", "a.sql").unwrap();
+        let b = file::parse_sql_file(
+            "select\n  1;\nselect 2;\n-- @label third\nselect 3;\n",
+            "b.sql",
+        )
+        .unwrap();
+        Scenario {
+            name: String::from("test"),
+            init_sqls: String::new(),
+            reset_sqls: String::new(),
+            check_sqls: vec![],
+            clusters: vec![],
+            session_per_file: false,
+            session_names: vec![String::from("A"), String::from("B")],
+            sqls_list: vec![a.sqls, b.sqls],
+            blocks_list: vec![a.blocks, b.blocks],
+            session_variables: vec![None, None],
+            constraints: vec![],
+            matrix: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_constraint() {
+        let scenario = scenario();
+        let cases = [
+            ("A:1 < B:3", (0, 0), (1, 1)),
+            ("A:3<B:1", (0, 2), (1, 0)),
+            ("2:5 < 1:2", (1, 2), (0, 1)),
+            ("B: third < A :2", (1, 2), (0, 1)),
+        ];
+        for &(constraint, before, after) in cases.iter() {
+            let parsed = scenario.parse_constraint(constraint).unwrap();
+            assert_eq!(
+                (parsed.before, parsed.after),
+                (before, after),
+                "{}",
+                constraint
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_constraint() {
+        let scenario = scenario();
+        let cases = [
+            // 格式错误
+            "A:1",
+            "A:1 > B:1",
+            "A1 < B:1",
+            "A:0 < B:1",
+            // 不存在的会话
+            "C:1 < B:1",
+            "3:1 < B:1",
+            "0:1 < B:1",
+            // 不是sql开始的行，或者超出文件的范围
+            "A:1 < B:2",
+            "A:1 < B:4",
+            "A:4 < B:1",
+            "A:1 < B:99",
+            // 不存在的标签
+            "B:third < A:third",
+        ];
+        for constraint in cases.iter() {
+            assert!(
+                scenario.parse_constraint(constraint).is_err(),
+                "{}",
+                constraint
+            );
+        }
+    }
+
+    fn matrix(vars: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        vars.iter()
+            .map(|(name, values)| {
+                let values = values.iter().map(|value| value.to_string()).collect();
+                (name.to_string(), values)
+            })
+            .collect()
+    }
+
+    /// 所有sql及会话变量中都有占位符的场景
+    fn template() -> Scenario {
+        let mut scenario = scenario();
+        scenario.init_sqls = String::from("create table t (a int) engine = ${engine};");
+        scenario.reset_sqls = String::from("truncate table t; -- ${engine}");
+        scenario.check_sqls = file::parse_sql_file("select * from t limit ${ n };", "check")
+            .unwrap()
+            .sqls;
+        scenario.sqls_list[1][1].text = String::from("insert into t values (${n})");
+        scenario.session_variables[0] = Some(String::from("SET SESSION tx_isolation = '${level}'"));
+        scenario.matrix = matrix(&[
+            ("engine", &["innodb", "rocksdb"]),
+            ("level", &["RC", "RR", "SERIALIZABLE"]),
+            ("n", &["1"]),
+        ]);
+        scenario
+    }
+
+    #[test]
+    fn test_combinations() {
+        // 没有模板变量时只有一个空的组合
+        assert_eq!(scenario().combinations(), vec![BTreeMap::new()]);
+        let combinations = template().combinations();
+        assert_eq!(combinations.len(), 6);
+        let values: Vec<Vec<&str>> = combinations
+            .iter()
+            .map(|combination| combination.values().map(String::as_str).collect())
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                vec!["innodb", "RC", "1"],
+                vec!["innodb", "RR", "1"],
+                vec!["innodb", "SERIALIZABLE", "1"],
+                vec!["rocksdb", "RC", "1"],
+                vec!["rocksdb", "RR", "1"],
+                vec!["rocksdb", "SERIALIZABLE", "1"],
+            ]
+        );
+        assert!(combinations
+            .iter()
+            .all(|combination| combination.keys().eq(["engine", "level", "n"].iter())));
+    }
+
+    #[test]
+    fn test_instantiate() {
+        let template = template();
+        let combination = &template.combinations()[4];
+        let scenario = template.instantiate(combination).unwrap();
+        assert_eq!(scenario.name, "test [engine=rocksdb, level=RR, n=1]");
+        assert!(scenario.matrix.is_empty());
+        assert_eq!(
+            scenario.init_sqls,
+            "create table t (a int) engine = rocksdb;"
+        );
+        assert_eq!(scenario.reset_sqls, "truncate table t; -- rocksdb");
+        assert_eq!(scenario.check_sqls[0].text, "select * from t limit 1");
+        assert_eq!(scenario.sqls_list[1][1].text, "insert into t values (1)");
+        assert_eq!(scenario.sqls_list[0][0].text, "select 1");
+        assert_eq!(
+            scenario.session_variables,
+            vec![Some(String::from("SET SESSION tx_isolation = 'RR'")), None]
+        );
+        // 没有模板变量时直接返回场景的拷贝
+        let plain = scenario.instantiate(&BTreeMap::new()).unwrap();
+        assert_eq!(plain.name, scenario.name);
+        assert_eq!(plain.sqls_list[1][1].text, "insert into t values (1)");
+    }
+
+    #[test]
+    fn test_instantiate_undefined() {
+        let mut scenario = template();
+        let combination = scenario.combinations().remove(0);
+        scenario.sqls_list[0][2].text = String::from("select ${m}");
+        let err = scenario.instantiate(&combination).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Undefined template variable `m` at (file 1, row 3)"
+        );
+        let mut scenario = template();
+        scenario.reset_sqls = String::from("truncate table ${table};");
+        let err = scenario.instantiate(&combination).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Undefined template variable `table` in reset sql"
+        );
+        let mut scenario = template();
+        scenario.sqls_list[1][2].text = String::from("select ${n");
+        assert!(scenario.instantiate(&combination).is_err());
+    }
+}
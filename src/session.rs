@@ -0,0 +1,317 @@
+use anyhow::{Error, Result};
+use futures::future;
+use mysql_async::prelude::*;
+use mysql_async::{Conn, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+use crate::file::Sql;
+
+/// 数据库检测到死锁时返回的错误码
+const ER_LOCK_DEADLOCK: u16 = 1213;
+/// 等锁超时的错误码，开启`innodb_rollback_on_timeout`时会回滚整个事务
+const ER_LOCK_WAIT_TIMEOUT: u16 = 1205;
+
+/// 捕获的一条sql的执行结果
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SqlResult {
+    /// 返回的行，每个值为其sql字面量，如`NULL`、`1`、`'a'`
+    pub rows: Vec<Vec<String>>,
+    pub affected_rows: u64,
+    pub last_insert_id: Option<u64>,
+    pub warnings: u16,
+    /// 发生期望的错误，或比较模式下发生错误时的错误码
+    pub error: Option<u16>,
+}
+
+/// sql的结果与`-- @expect-error`或`-- @expect-rows`不一致，带上实际
+/// 的结果，比较模式下作为sql的结果记录
+#[derive(Debug)]
+pub struct Mismatch {
+    message: String,
+    pub result: SqlResult,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+/// 执行中的sql
+struct Running {
+    /// sql在批次中的位置，从0开始
+    pos: usize,
+    /// 是否已经被标记为阻塞
+    blocked: bool,
+    handle: JoinHandle<(Conn, Result<Option<SqlResult>>, Duration)>,
+}
+
+/// 一个sql文件对应的会话，sql在后台任务中执行，
+/// 所以一个会话阻塞时，其它会话还可以继续执行
+pub struct Session {
+    /// 执行sql时，连接会被移到后台任务
+    conn: Option<Conn>,
+    running: Option<Running>,
+    /// 由于会话繁忙而延后执行的sql在批次中的位置
+    deferred: VecDeque<usize>,
+}
+
+impl Session {
+    pub fn new(conn: Conn) -> Self {
+        Session {
+            conn: Some(conn),
+            running: None,
+            deferred: VecDeque::new(),
+        }
+    }
+
+    /// 没有执行中及延后执行的sql
+    pub fn is_idle(&self) -> bool {
+        self.running.is_none() && self.deferred.is_empty()
+    }
+
+    /// 有执行中且还没被标记为阻塞的sql
+    pub fn is_settling(&self) -> bool {
+        match &self.running {
+            Some(running) => !running.blocked,
+            None => false,
+        }
+    }
+
+    pub fn defer(&mut self, pos: usize) {
+        self.deferred.push_back(pos);
+    }
+
+    /// 在后台任务执行sql
+    pub fn start(&mut self, pos: usize, sql: Sql, capture: bool) {
+        let mut conn = self.conn.take().expect("session is running");
+        let handle = tokio::spawn(async move {
+            let begin = Instant::now();
+            let result = execute(&mut conn, &sql, capture).await;
+            (conn, result, begin.elapsed())
+        });
+        self.running = Some(Running {
+            pos,
+            blocked: false,
+            handle,
+        });
+    }
+
+    /// 取出下一个延后执行的sql，会话还在执行sql时返回None
+    pub fn next_deferred(&mut self) -> Option<usize> {
+        if self.running.is_some() {
+            return None;
+        }
+        self.deferred.pop_front()
+    }
+
+    /// 把执行中的sql标记为阻塞，返回新被标记的sql位置
+    pub fn mark_blocked(&mut self) -> Option<usize> {
+        match &mut self.running {
+            Some(running) if !running.blocked => {
+                running.blocked = true;
+                Some(running.pos)
+            }
+            _ => None,
+        }
+    }
+
+    /// 回滚空闲连接上的事务以释放锁
+    async fn rollback_idle(&mut self) {
+        if let Some(conn) = self.conn.as_mut() {
+            if let Err(e) = conn.query_drop("ROLLBACK").await {
+                log::warn!("rollback abandoned session failed:\n{}", e);
+            }
+        }
+    }
+
+    /// 会话空闲时返回连接，用于批次结束后的清理
+    pub fn conn_mut(&mut self) -> Option<&mut Conn> {
+        self.conn.as_mut()
+    }
+}
+
+/// 等待任一会话执行完sql，最多等待`threshold`
+///
+/// 返回会话的索引，sql在批次中的位置，执行时间及捕获的结果，没有
+/// sql在`threshold`内完成时返回None
+pub async fn wait_any(
+    sessions: &mut [Session],
+    threshold: Duration,
+) -> Option<(usize, usize, Result<(Duration, Option<SqlResult>)>)> {
+    let mut session_idxs = vec![];
+    let mut handles = vec![];
+    for (idx, session) in sessions.iter_mut().enumerate() {
+        if let Some(running) = &mut session.running {
+            session_idxs.push(idx);
+            handles.push(&mut running.handle);
+        }
+    }
+    if handles.is_empty() {
+        return None;
+    }
+    let (joined, i, _) = match tokio::time::timeout(threshold, future::select_all(handles)).await
+    {
+        Ok(r) => r,
+        Err(_) => return None,
+    };
+    let session = &mut sessions[session_idxs[i]];
+    let pos = session.running.take().unwrap().pos;
+    let result = match joined {
+        Ok((conn, result, time)) => {
+            session.conn = Some(conn);
+            result.map(|captured| (time, captured))
+        }
+        Err(e) => Err(Error::from(e)),
+    };
+    Some((session_idxs[i], pos, result))
+}
+
+/// 放弃所有会话延后执行的sql，回滚事务以释放锁，并等待执行中的sql
+/// 结束，忽略它们的结果
+///
+/// 先回滚所有空闲的会话，再等待执行中的sql，执行中的sql结束后立即
+/// 回滚它的会话。否则被其它会话的锁阻塞的sql要等到锁等待超时才结束
+pub async fn abandon(sessions: &mut [Session]) {
+    for session in sessions.iter_mut() {
+        session.deferred.clear();
+        session.rollback_idle().await;
+    }
+    loop {
+        let mut session_idxs = vec![];
+        let mut handles = vec![];
+        for (idx, session) in sessions.iter_mut().enumerate() {
+            if let Some(running) = &mut session.running {
+                session_idxs.push(idx);
+                handles.push(&mut running.handle);
+            }
+        }
+        if handles.is_empty() {
+            return;
+        }
+        let (joined, i, _) = future::select_all(handles).await;
+        let session = &mut sessions[session_idxs[i]];
+        session.running = None;
+        match joined {
+            Ok((conn, _, _)) => {
+                session.conn = Some(conn);
+                session.rollback_idle().await;
+            }
+            Err(e) => log::warn!("waiting abandoned sql failed:\n{}", e),
+        }
+    }
+}
+
+/// 是否数据库检测到死锁而返回的错误
+pub fn is_deadlock(e: &Error) -> bool {
+    match e.downcast_ref::<mysql_async::Error>() {
+        Some(mysql_async::Error::Server(server_error)) => server_error.code == ER_LOCK_DEADLOCK,
+        _ => false,
+    }
+}
+
+/// sql的执行结果是否为可能回滚整个事务的错误，包括期望的错误
+pub fn rolls_back_transaction(result: &Result<Option<SqlResult>>, sql: &Sql) -> bool {
+    let code = match result {
+        // 成功时有期望的错误码，说明发生了期望的错误
+        Ok(_) => sql.expect_error,
+        Err(e) => match e.downcast_ref::<Mismatch>() {
+            Some(mismatch) => mismatch.result.error,
+            None => match e.downcast_ref::<mysql_async::Error>() {
+                Some(mysql_async::Error::Server(server_error)) => Some(server_error.code),
+                _ => None,
+            },
+        },
+    };
+    code == Some(ER_LOCK_DEADLOCK) || code == Some(ER_LOCK_WAIT_TIMEOUT)
+}
+
+/// 执行一条sql，并检查`-- @expect-error`及`-- @expect-rows`指令
+///
+/// 发生期望的错误时返回成功，结果与期望不一致时返回错误。`capture`
+/// 为true时返回捕获的结果
+pub async fn execute(conn: &mut Conn, sql: &Sql, capture: bool) -> Result<Option<SqlResult>> {
+    let result = if capture || sql.expect_rows.is_some() {
+        conn.query::<Row, _>(&sql.text).await
+    } else {
+        conn.query_drop(&sql.text).await.map(|_| vec![])
+    };
+    let rows = match (result, sql.expect_error) {
+        (Err(mysql_async::Error::Server(e)), Some(code)) if e.code == code => {
+            return Ok(if capture {
+                Some(SqlResult {
+                    error: Some(code),
+                    ..Default::default()
+                })
+            } else {
+                None
+            });
+        }
+        (Err(mysql_async::Error::Server(e)), Some(code)) => {
+            return Err(Error::from(Mismatch {
+                message: format!("Expected error {} but got:\n{}", code, e),
+                result: SqlResult {
+                    error: Some(e.code),
+                    ..Default::default()
+                },
+            }))
+        }
+        (Err(e), Some(code)) => {
+            return Err(Error::msg(format!(
+                "Expected error {} but got:\n{}",
+                code, e
+            )))
+        }
+        (Err(e), None) => return Err(Error::from(e)),
+        (Ok(rows), Some(code)) => {
+            return Err(Error::from(Mismatch {
+                message: format!("Expected error {} but succeeded", code),
+                result: capture_result(conn, rows),
+            }))
+        }
+        (Ok(rows), None) => rows,
+    };
+    if let Some(expected) = sql.expect_rows {
+        // 没有返回行时，按影响的行数检查
+        let amount = if rows.is_empty() {
+            conn.affected_rows()
+        } else {
+            rows.len() as u64
+        };
+        if amount != expected {
+            return Err(Error::from(Mismatch {
+                message: format!("Expected {} rows but got {}", expected, amount),
+                result: capture_result(conn, rows),
+            }));
+        }
+    }
+    if !capture {
+        return Ok(None);
+    }
+    Ok(Some(capture_result(conn, rows)))
+}
+
+/// 取出刚执行完的sql的结果
+fn capture_result(conn: &Conn, rows: Vec<Row>) -> SqlResult {
+    SqlResult {
+        rows: rows
+            .into_iter()
+            .map(|row| {
+                row.unwrap()
+                    .iter()
+                    .map(|value| value.as_sql(false))
+                    .collect()
+            })
+            .collect(),
+        affected_rows: conn.affected_rows(),
+        last_insert_id: conn.last_insert_id(),
+        warnings: conn.get_warnings(),
+        error: None,
+    }
+}
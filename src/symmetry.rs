@@ -0,0 +1,108 @@
+use num_bigint::BigUint;
+use num_traits::One;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::algo::Pruner;
+use crate::file::Sql;
+
+/// 找出内容相同的sql文件，同样的文件多次指定，且会话变量也相同时，
+/// 它们对应的会话可以互换。只返回包含至少两个非空文件的分组，组内
+/// 按文件索引排序
+///
+/// # Arguments
+///
+/// * `sqls_list` - 每个文件的sql
+/// * `blocks_list` - 每个文件的块
+/// * `session_variables` - 每个文件的会话设置的会话变量，可以为空
+pub fn identical_groups(
+    sqls_list: &[Vec<Sql>],
+    blocks_list: &[Vec<Range<usize>>],
+    session_variables: &[Option<String>],
+) -> Vec<Vec<usize>> {
+    let variables = |file_idx: usize| session_variables.get(file_idx).and_then(Option::as_ref);
+    let mut groups: Vec<Vec<usize>> = vec![];
+    for file_idx in 0..sqls_list.len() {
+        if sqls_list[file_idx].is_empty() {
+            continue;
+        }
+        let group = groups.iter_mut().find(|group| {
+            sqls_list[group[0]] == sqls_list[file_idx]
+                && blocks_list[group[0]] == blocks_list[file_idx]
+                && variables(group[0]) == variables(file_idx)
+        });
+        match group {
+            Some(group) => group.push(file_idx),
+            None => groups.push(vec![file_idx]),
+        }
+    }
+    groups.retain(|group| group.len() > 1);
+    groups
+}
+
+/// 每组相同文件的数量的阶乘之积，即每种交错排列在互换相同文件后
+/// 得到的等价交错排列的数量
+pub fn symmetry_factor(groups: &[Vec<usize>]) -> BigUint {
+    let mut factor = BigUint::one();
+    for group in groups {
+        for i in 1..=group.len() {
+            factor *= i;
+        }
+    }
+    factor
+}
+
+/// 对称归约的剪枝条件，互换相同文件得到的交错排列中，只保留相同
+/// 文件按索引顺序开始执行的一个：同一组中，前一个文件开始执行后，
+/// 后一个文件才能开始执行
+///
+/// # Arguments
+///
+/// * `groups` - 内容相同的文件分组
+/// * `file_amount` - 文件的数量
+pub fn into_pruner(groups: &[Vec<usize>], file_amount: usize) -> Pruner {
+    // 每个文件在同一组中的前一个文件
+    let mut prevs = vec![None; file_amount];
+    for group in groups {
+        for pair in group.windows(2) {
+            prevs[pair[1]] = Some(pair[0]);
+        }
+    }
+    Arc::new(move |_, curs, i| match prevs[i] {
+        Some(prev) if curs[i] == 0 => curs[prev] > 0,
+        _ => true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sqls(texts: &[&str]) -> Vec<Sql> {
+        texts
+            .iter()
+            .map(|text| Sql {
+                text: String::from(*text),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_identical_groups() {
+        let sqls_list = vec![
+            sqls(&["a", "b"]),
+            sqls(&["a", "b"]),
+            sqls(&["a", "b"]),
+            sqls(&["c"]),
+        ];
+        let blocks_list = vec![vec![0..1, 1..2], vec![0..1, 1..2], vec![0..2], vec![0..1]];
+        assert_eq!(
+            identical_groups(&sqls_list, &blocks_list, &[]),
+            vec![vec![0, 1]]
+        );
+        // 会话变量不同的会话不能互换
+        let session_variables = vec![Some(String::from("SET SESSION a = 1")), None, None, None];
+        assert!(identical_groups(&sqls_list, &blocks_list, &session_variables).is_empty());
+    }
+}